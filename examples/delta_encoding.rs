@@ -0,0 +1,59 @@
+use metrics_sqlite::{DeltaEncoding, EncodedValue, MetricsDb};
+use std::time::{Duration, Instant};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| "metrics.db".to_string());
+    let scale = args.next().map(|s| s.parse().unwrap()).unwrap_or(0.001);
+    let encoding = DeltaEncoding::new(scale, 256);
+    let mut db = MetricsDb::new(path).expect("Failed to open DB");
+
+    let mut samples = 0;
+    let mut plain_size = 0;
+    let mut encoded_size = 0;
+    let mut query_time = Duration::ZERO;
+    let mut decode_time = Duration::ZERO;
+    for key in db.available_keys().expect("Failed to load keys") {
+        let start = Instant::now();
+        let values: Vec<f64> = db
+            .metrics_for_key(&key, None)
+            .expect("Failed to load metrics")
+            .into_iter()
+            .map(|m| m.value.0)
+            .collect();
+        query_time += start.elapsed();
+
+        let encoded = encoding.encode(&values);
+        let start = Instant::now();
+        let decoded = encoding.decode(&encoded);
+        decode_time += start.elapsed();
+
+        let max_error = values
+            .iter()
+            .zip(&decoded)
+            .map(|(a, b)| (a - b).abs())
+            .filter(|e| e.is_finite())
+            .fold(0.0, f64::max);
+        let size: usize = encoded.iter().map(EncodedValue::stored_size).sum();
+        println!(
+            "{}: {} samples, {} -> {} value bytes, max error {}",
+            key,
+            values.len(),
+            values.len() * 8,
+            size,
+            max_error
+        );
+        samples += values.len();
+        plain_size += values.len() * 8;
+        encoded_size += size;
+    }
+    println!(
+        "Total: {} samples, {} -> {} value bytes ({:.1}%), query {:?}, decode {:?}",
+        samples,
+        plain_size,
+        encoded_size,
+        encoded_size as f64 / plain_size.max(1) as f64 * 100.0,
+        query_time,
+        decode_time
+    );
+}
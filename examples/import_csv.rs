@@ -4,6 +4,9 @@ fn main() {
     pretty_env_logger::formatted_builder()
         .filter(None, log::LevelFilter::Debug)
         .init();
-    MetricsDb::import_from_csv("metrics.csv", "imported.db").unwrap();
-    println!("Success");
+    let stats = MetricsDb::import_from_csv("metrics.csv", "imported.db").unwrap();
+    println!(
+        "Success: {} read, {} inserted, {} skipped",
+        stats.read, stats.inserted, stats.skipped
+    );
 }
@@ -3,7 +3,7 @@ use metrics_sqlite::MetricsDb;
 fn main() {
     let mut db = MetricsDb::new("metrics.db").unwrap();
     println!("Keys: {}", db.available_keys().unwrap().join(", "));
-    let sessions = db.sessions();
+    let sessions = db.sessions().unwrap();
     for (i, s) in sessions.iter().enumerate() {
         println!(
             "Session {}: {:.2}s long ({:.2} - {:.2})",
@@ -1,5 +1,5 @@
 use metrics::{counter, gauge};
-use metrics_sqlite::SqliteExporter;
+use metrics_sqlite::{RetentionPolicy, SqliteExporter};
 use std::time::Duration;
 
 fn setup_metrics() {
@@ -9,7 +9,11 @@ fn setup_metrics() {
         "metrics-large.db",
     )
     .expect("Failed to create SqliteExporter");
-    exporter.set_periodic_housekeeping(Some(Duration::from_secs(10)), None, Some(1_000_000));
+    exporter
+        .set_periodic_housekeeping(Some(
+            RetentionPolicy::new(Duration::from_secs(10)).record_limit(1_000_000),
+        ))
+        .expect("Invalid retention policy");
     exporter
         .install()
         .expect("Failed to install SqliteExporter");
@@ -0,0 +1,2 @@
+DROP INDEX histogram_summaries_key_id_idx;
+DROP TABLE histogram_summaries;
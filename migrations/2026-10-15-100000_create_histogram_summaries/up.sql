@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS histogram_summaries (
+                                       id integer NOT NULL primary key autoincrement,
+                                       timestamp real NOT NULL,
+                                       metric_key_id integer NOT NULL,
+                                       summary text NOT NULL
+);
+CREATE INDEX IF NOT EXISTS histogram_summaries_key_id_idx ON histogram_summaries (metric_key_id, timestamp);
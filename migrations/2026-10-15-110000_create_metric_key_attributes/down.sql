@@ -0,0 +1,2 @@
+DROP INDEX metric_key_attributes_key_name_idx;
+DROP TABLE metric_key_attributes;
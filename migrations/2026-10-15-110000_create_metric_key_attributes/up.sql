@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS metric_key_attributes (
+                                       id integer NOT NULL primary key autoincrement,
+                                       metric_key_id integer NOT NULL,
+                                       name text NOT NULL,
+                                       value text NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS metric_key_attributes_key_name_idx ON metric_key_attributes (metric_key_id, name);
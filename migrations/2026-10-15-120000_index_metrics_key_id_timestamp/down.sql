@@ -0,0 +1 @@
+DROP INDEX metrics_key_id_timestamp_idx;
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS metrics_key_id_timestamp_idx ON metrics (metric_key_id, timestamp);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS db_metadata (
+                                       name text NOT NULL primary key,
+                                       value text NOT NULL
+);
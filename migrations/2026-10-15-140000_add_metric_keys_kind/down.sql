@@ -0,0 +1 @@
+ALTER TABLE metric_keys DROP COLUMN kind;
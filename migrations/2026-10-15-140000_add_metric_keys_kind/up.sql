@@ -0,0 +1 @@
+ALTER TABLE metric_keys ADD COLUMN kind text;
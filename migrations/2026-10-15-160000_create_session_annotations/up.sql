@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS session_annotations (
+                                       id integer NOT NULL primary key autoincrement,
+                                       session_start double NOT NULL,
+                                       note text NOT NULL
+);
+CREATE INDEX IF NOT EXISTS session_annotations_session_start_idx ON session_annotations (session_start);
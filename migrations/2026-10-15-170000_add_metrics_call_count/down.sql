@@ -0,0 +1 @@
+ALTER TABLE metrics DROP COLUMN call_count;
@@ -0,0 +1,2 @@
+DROP INDEX metrics_archive_key_id_idx;
+DROP TABLE metrics_archive;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS metrics_archive (
+                                       id integer NOT NULL primary key autoincrement,
+                                       metric_key_id integer NOT NULL,
+                                       timestamp real NOT NULL,
+                                       resolution real NOT NULL,
+                                       sample_count integer NOT NULL,
+                                       min_value real NOT NULL,
+                                       max_value real NOT NULL,
+                                       mean_value real NOT NULL
+);
+CREATE INDEX IF NOT EXISTS metrics_archive_key_id_idx ON metrics_archive (metric_key_id, timestamp);
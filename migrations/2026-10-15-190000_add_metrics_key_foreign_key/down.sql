@@ -0,0 +1,15 @@
+CREATE TABLE metrics_old (
+                                       id integer NOT NULL primary key autoincrement,
+                                       timestamp real NOT NULL,
+                                       metric_key_id integer NOT NULL,
+                                       value integer NOT NULL,
+                                       timestamp_text text,
+                                       call_count bigint
+);
+INSERT INTO metrics_old (id, timestamp, metric_key_id, value, timestamp_text, call_count)
+    SELECT id, timestamp, metric_key_id, value, timestamp_text, call_count FROM metrics;
+DROP TABLE metrics;
+ALTER TABLE metrics_old RENAME TO metrics;
+CREATE INDEX IF NOT EXISTS metrics_timestamp_idx ON metrics (timestamp);
+CREATE INDEX IF NOT EXISTS metrics_key_id_idx ON metrics (metric_key_id);
+CREATE INDEX IF NOT EXISTS metrics_key_id_timestamp_idx ON metrics (metric_key_id, timestamp);
@@ -0,0 +1,18 @@
+-- SQLite can't add constraints to existing tables so metrics is rebuilt, existing samples without a
+-- key entry are copied as is & reported by PRAGMA foreign_key_check
+CREATE TABLE metrics_new (
+                                       id integer NOT NULL primary key autoincrement,
+                                       timestamp real NOT NULL,
+                                       metric_key_id integer NOT NULL,
+                                       value integer NOT NULL,
+                                       timestamp_text text,
+                                       call_count bigint,
+                                       FOREIGN KEY(metric_key_id) REFERENCES metric_keys(id)
+);
+INSERT INTO metrics_new (id, timestamp, metric_key_id, value, timestamp_text, call_count)
+    SELECT id, timestamp, metric_key_id, value, timestamp_text, call_count FROM metrics;
+DROP TABLE metrics;
+ALTER TABLE metrics_new RENAME TO metrics;
+CREATE INDEX IF NOT EXISTS metrics_timestamp_idx ON metrics (timestamp);
+CREATE INDEX IF NOT EXISTS metrics_key_id_idx ON metrics (metric_key_id);
+CREATE INDEX IF NOT EXISTS metrics_key_id_timestamp_idx ON metrics (metric_key_id, timestamp);
@@ -0,0 +1,2 @@
+DROP INDEX events_timestamp_idx;
+DROP TABLE events;
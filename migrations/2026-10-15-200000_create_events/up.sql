@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS events (
+                                       id integer NOT NULL primary key autoincrement,
+                                       timestamp real NOT NULL,
+                                       name text NOT NULL,
+                                       value text NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_timestamp_idx ON events (timestamp);
@@ -0,0 +1,2 @@
+DROP INDEX metric_keys_key_labels_idx;
+ALTER TABLE metric_keys DROP COLUMN labels;
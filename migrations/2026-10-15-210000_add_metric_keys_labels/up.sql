@@ -0,0 +1,2 @@
+ALTER TABLE metric_keys ADD COLUMN labels text NOT NULL DEFAULT '';
+CREATE INDEX IF NOT EXISTS metric_keys_key_labels_idx ON metric_keys (key, labels);
@@ -0,0 +1,2 @@
+DROP INDEX histogram_buckets_key_id_idx;
+DROP TABLE histogram_buckets;
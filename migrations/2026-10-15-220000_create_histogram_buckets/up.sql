@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS histogram_buckets (
+                                       id integer NOT NULL primary key autoincrement,
+                                       timestamp real NOT NULL,
+                                       metric_key_id integer NOT NULL,
+                                       upper_bound real NOT NULL,
+                                       count integer NOT NULL,
+                                       FOREIGN KEY(metric_key_id) REFERENCES metric_keys(id)
+);
+CREATE INDEX IF NOT EXISTS histogram_buckets_key_id_idx ON histogram_buckets (metric_key_id, timestamp);
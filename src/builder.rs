@@ -0,0 +1,728 @@
+//! Builder for configuring a `SqliteExporter`
+use crate::models::{set_db_metadata, MetricKey};
+#[cfg(feature = "test-util")]
+use crate::RecordingObserver;
+use crate::{
+    path_with_suffix, run_worker, set_busy_timeout, setup_db_with_migrations, total_bytes,
+    InnerState, KeyNormalizer, MetricsError, Result, RetentionPolicy, SqliteExporter,
+    BACKGROUND_CHANNEL_LIMIT, FLUSH_QUEUE_LIMIT,
+};
+use diesel_migrations::EmbeddedMigrations;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Default interval metrics are flushed to SQLite/disk at
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+/// `db_metadata` entry name for `app_name()`
+const METADATA_APP_NAME: &str = "app_name";
+/// `db_metadata` entry name for `app_version()`
+const METADATA_APP_VERSION: &str = "app_version";
+/// `db_metadata` entry name for `hostname()`
+const METADATA_HOSTNAME: &str = "hostname";
+/// Default name of worker thread
+const DEFAULT_THREAD_NAME: &str = "metrics-sqlite: worker";
+
+/// Deadband filtering for gauges, only recording values that differ from the last recorded value
+/// by more than `epsilon`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Deadband {
+    /// Max difference from last recorded value that's still dropped
+    pub epsilon: f64,
+    /// Records anyway once this long has passed since last recorded sample, so flat signals
+    /// still produce periodic proof-of-life samples
+    pub max_interval: Option<Duration>,
+}
+
+/// Reduces a histogram's observations to one stored value per flush, for high rate histograms
+/// where only a per-interval figure is charted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HistogramReducer {
+    /// Last observation
+    Last,
+    /// Lowest observation
+    Min,
+    /// Highest observation
+    Max,
+    /// Average of observations
+    Mean,
+}
+
+/// Linear transform applied to a key's values before storing, `stored = raw * scale + offset`,
+/// e.g. converting raw ADC readings to engineering units
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ValueTransform {
+    /// Multiplier applied to raw value
+    pub scale: f64,
+    /// Added after scaling
+    pub offset: f64,
+}
+
+impl ValueTransform {
+    /// Applies transform to given raw value
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.scale + self.offset
+    }
+}
+
+/// Builder for `SqliteExporter`, covering options beyond what `SqliteExporter::new()` takes
+///
+/// ```no_run
+/// # use metrics_sqlite::SqliteExporter;
+/// # use std::time::Duration;
+/// let exporter = SqliteExporter::builder()
+///     .flush_interval(Duration::from_secs(30))
+///     .retention(Duration::from_secs(60 * 60 * 24 * 7))
+///     .initial_flush_samples(50)
+///     .build("metrics.db")
+///     .expect("Failed to create SqliteExporter");
+/// ```
+#[derive(Clone)]
+pub struct SqliteExporterBuilder {
+    flush_interval: Duration,
+    min_flush_interval: Duration,
+    retention: Option<Duration>,
+    record_limit: Option<usize>,
+    housekeeping_period: Option<Duration>,
+    channel_capacity: usize,
+    flush_queue_limit: usize,
+    initial_flush_samples: Option<usize>,
+    gauge_deadband: Option<Deadband>,
+    key_deadbands: HashMap<String, Deadband>,
+    priority_keys: HashSet<String>,
+    delta_gauges: HashSet<String>,
+    key_normalizer: Option<KeyNormalizer>,
+    checkpoint_interval: Option<Duration>,
+    vacuum_after_housekeeping: bool,
+    wal_mode: bool,
+    busy_timeout: Option<Duration>,
+    record_call_counts: bool,
+    max_keys: Option<usize>,
+    park_when_idle: Option<Duration>,
+    dedup_registrations: bool,
+    dropped_count_key: Option<String>,
+    key_transforms: HashMap<String, ValueTransform>,
+    raw_mode: bool,
+    require_gauge_baseline: bool,
+    #[cfg(feature = "iso_timestamps")]
+    iso_timestamps: bool,
+    histogram_reducer: Option<HistogramReducer>,
+    histogram_bounds: Option<Vec<f64>>,
+    promote_on_shutdown: bool,
+    drop_timeout: Option<Duration>,
+    warn_size: Option<u64>,
+    fail_size: Option<u64>,
+    flush_retries: u32,
+    flush_retry_delay: Option<Duration>,
+    flush_retry_timeout: Option<Duration>,
+    thread_name: String,
+    stack_size: Option<usize>,
+    extra_migrations: Option<&'static EmbeddedMigrations>,
+    metadata: Vec<(&'static str, String)>,
+    #[cfg(feature = "test-util")]
+    observer: Option<RecordingObserver>,
+}
+
+impl Default for SqliteExporterBuilder {
+    fn default() -> Self {
+        SqliteExporterBuilder {
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            min_flush_interval: Duration::ZERO,
+            retention: None,
+            record_limit: None,
+            housekeeping_period: None,
+            channel_capacity: BACKGROUND_CHANNEL_LIMIT,
+            flush_queue_limit: FLUSH_QUEUE_LIMIT,
+            initial_flush_samples: None,
+            gauge_deadband: None,
+            key_deadbands: HashMap::new(),
+            priority_keys: HashSet::new(),
+            delta_gauges: HashSet::new(),
+            key_normalizer: None,
+            checkpoint_interval: None,
+            vacuum_after_housekeeping: false,
+            wal_mode: true,
+            busy_timeout: None,
+            record_call_counts: false,
+            max_keys: None,
+            park_when_idle: None,
+            dedup_registrations: true,
+            dropped_count_key: None,
+            key_transforms: HashMap::new(),
+            raw_mode: false,
+            require_gauge_baseline: false,
+            #[cfg(feature = "iso_timestamps")]
+            iso_timestamps: false,
+            histogram_reducer: None,
+            histogram_bounds: None,
+            promote_on_shutdown: false,
+            drop_timeout: None,
+            warn_size: None,
+            fail_size: None,
+            flush_retries: 0,
+            flush_retry_delay: None,
+            flush_retry_timeout: None,
+            thread_name: DEFAULT_THREAD_NAME.to_string(),
+            stack_size: None,
+            extra_migrations: None,
+            metadata: Vec::new(),
+            #[cfg(feature = "test-util")]
+            observer: None,
+        }
+    }
+}
+impl fmt::Debug for SqliteExporterBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut builder = f.debug_struct("SqliteExporterBuilder");
+        builder.field("flush_interval", &self.flush_interval);
+        builder.field("min_flush_interval", &self.min_flush_interval);
+        builder.field("retention", &self.retention);
+        builder.field("record_limit", &self.record_limit);
+        builder.field("housekeeping_period", &self.housekeeping_period);
+        builder.field("channel_capacity", &self.channel_capacity);
+        builder.field("flush_queue_limit", &self.flush_queue_limit);
+        builder.field("initial_flush_samples", &self.initial_flush_samples);
+        builder.field("gauge_deadband", &self.gauge_deadband);
+        builder.field("key_deadbands", &self.key_deadbands);
+        builder.field("priority_keys", &self.priority_keys);
+        builder.field("delta_gauges", &self.delta_gauges);
+        builder.field("key_normalizer", &self.key_normalizer.is_some());
+        builder.field("checkpoint_interval", &self.checkpoint_interval);
+        builder.field("vacuum_after_housekeeping", &self.vacuum_after_housekeeping);
+        builder.field("wal_mode", &self.wal_mode);
+        builder.field("busy_timeout", &self.busy_timeout);
+        builder.field("record_call_counts", &self.record_call_counts);
+        builder.field("max_keys", &self.max_keys);
+        builder.field("park_when_idle", &self.park_when_idle);
+        builder.field("dedup_registrations", &self.dedup_registrations);
+        builder.field("dropped_count_key", &self.dropped_count_key);
+        builder.field("key_transforms", &self.key_transforms);
+        builder.field("raw_mode", &self.raw_mode);
+        builder.field("require_gauge_baseline", &self.require_gauge_baseline);
+        #[cfg(feature = "iso_timestamps")]
+        builder.field("iso_timestamps", &self.iso_timestamps);
+        builder.field("histogram_reducer", &self.histogram_reducer);
+        builder.field("histogram_bounds", &self.histogram_bounds);
+        builder.field("promote_on_shutdown", &self.promote_on_shutdown);
+        builder.field("drop_timeout", &self.drop_timeout);
+        builder.field("warn_size", &self.warn_size);
+        builder.field("fail_size", &self.fail_size);
+        builder.field("flush_retries", &self.flush_retries);
+        builder.field("flush_retry_delay", &self.flush_retry_delay);
+        builder.field("flush_retry_timeout", &self.flush_retry_timeout);
+        builder.field("thread_name", &self.thread_name);
+        builder.field("stack_size", &self.stack_size);
+        builder.field("extra_migrations", &self.extra_migrations.is_some());
+        builder.field("metadata", &self.metadata);
+        #[cfg(feature = "test-util")]
+        builder.field("observer", &self.observer.is_some());
+        builder.finish()
+    }
+}
+
+impl SqliteExporterBuilder {
+    /// Creates a builder with default settings, flushing every 30s & keeping data indefinitely
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how often metrics are flushed to SQLite/disk
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Sets minimum time between flushes, deferring flushes the interval would trigger sooner
+    /// so bursts are batched into fewer transactions, none by default
+    ///
+    /// Priority keys, reaching the flush queue limit & stopping the exporter still flush
+    /// immediately
+    pub fn min_flush_interval(mut self, min_flush_interval: Duration) -> Self {
+        self.min_flush_interval = min_flush_interval;
+        self
+    }
+
+    /// Sets how long data is kept before deleting, performed when building the exporter & every
+    /// `housekeeping_period` if set
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Sets max number of records to keep, enforced like `retention()`
+    ///
+    /// Anything over limit + 25% of limit is removed, oldest first
+    pub fn record_limit(mut self, record_limit: usize) -> Self {
+        self.record_limit = Some(record_limit);
+        self
+    }
+
+    /// Runs house keeping every `period` while recording, applying `retention()` &
+    /// `record_limit()`, see `SqliteExporter::set_periodic_housekeeping()` for finer control
+    pub fn housekeeping_period(mut self, period: Duration) -> Self {
+        self.housekeeping_period = Some(period);
+        self
+    }
+
+    /// Sets how many events can wait for the worker before new ones are dropped, 8000 by default
+    ///
+    /// The channel allocates its full capacity up front, roughly 128 bytes per event, so larger
+    /// values trade memory for headroom when metrics are emitted in bursts or the database stalls.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Sets how many samples can be queued before flushing early, 1000 by default
+    ///
+    /// Larger values batch more samples per transaction, reducing disk writes at the cost of
+    /// holding more samples (and their key names) in memory & losing more of them on a crash.
+    pub fn flush_queue_limit(mut self, limit: usize) -> Self {
+        self.flush_queue_limit = limit;
+        self
+    }
+
+    /// Flushes as soon as this many samples are queued after startup, instead of waiting for the
+    /// first flush interval, so early data is visible quickly. Regular flushing resumes afterwards.
+    pub fn initial_flush_samples(mut self, samples: usize) -> Self {
+        self.initial_flush_samples = Some(samples);
+        self
+    }
+
+    /// Sets deadband applied to all gauges, see `key_deadband()` to override per key
+    pub fn gauge_deadband(mut self, deadband: Deadband) -> Self {
+        self.gauge_deadband = Some(deadband);
+        self
+    }
+
+    /// Sets deadband for given gauge key, overriding `gauge_deadband()`
+    pub fn key_deadband(mut self, key: &str, deadband: Deadband) -> Self {
+        self.key_deadbands.insert(key.to_string(), deadband);
+        self
+    }
+
+    /// Sets keys that flush immediately when a sample is queued for them, instead of waiting for
+    /// the flush interval, for critical metrics that must persist promptly
+    pub fn priority_keys<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.priority_keys.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets gauge keys stored as the signed change each update makes rather than the resulting
+    /// value, e.g. a balance's change per transaction
+    ///
+    /// Gauge values are still tracked so absolute sets store the change from the previous value.
+    /// Deadbands aren't applied to these keys.
+    pub fn gauge_deltas<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.delta_gauges.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets function applied to every key name before storing, consolidating naming variants like
+    /// `http.requests` & `http_requests` into one series
+    ///
+    /// Key names given to other builder options are normalized too. Pass the same function to
+    /// `MetricsDb::set_key_normalizer()` so queries resolve keys the same way.
+    pub fn key_normalizer<F>(mut self, normalizer: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.key_normalizer = Some(Arc::new(normalizer));
+        self
+    }
+
+    /// Sets transform applied to given key's values before storing
+    ///
+    /// Transform is stored as `scale` & `offset` key attributes so consumers know values are
+    /// already scaled. Gauge deadbands apply to raw values.
+    pub fn key_transform(mut self, key: &str, transform: ValueTransform) -> Self {
+        self.key_transforms.insert(key.to_string(), transform);
+        self
+    }
+
+    /// Stores every value exactly as recorded instead of accumulating counter totals & gauge
+    /// values, treating each as an independent sample & keeping no per-key state
+    ///
+    /// Counter increments are stored as the increment, gauge increments & decrements as the
+    /// signed change. Gauge deadbands aren't applied.
+    pub fn raw_mode(mut self, raw_mode: bool) -> Self {
+        self.raw_mode = raw_mode;
+        self
+    }
+
+    /// Drops gauge increments & decrements until an absolute value has been set for the gauge,
+    /// instead of applying them to an assumed starting value of 0
+    pub fn require_gauge_baseline(mut self, require: bool) -> Self {
+        self.require_gauge_baseline = require;
+        self
+    }
+
+    /// Additionally stores each sample's timestamp as UTC ISO-8601 text in `timestamp_text`, so
+    /// the database is readable in a generic SQLite browser. The numeric timestamp remains the one
+    /// queried & ordered by.
+    #[cfg(feature = "iso_timestamps")]
+    pub fn iso_timestamps(mut self, enabled: bool) -> Self {
+        self.iso_timestamps = enabled;
+        self
+    }
+
+    /// Stores one value per histogram per flush, reduced from its observations, instead of every
+    /// observation. Stored value is timestamped with the last observation.
+    pub fn histogram_reducer(mut self, reducer: HistogramReducer) -> Self {
+        self.histogram_reducer = Some(reducer);
+        self
+    }
+
+    /// Counts histogram observations into buckets with given ascending upper bounds, plus one for
+    /// anything above the last, storing each key's counts per flush in the `histogram_buckets`
+    /// table alongside its samples, see `MetricsDb::histogram_buckets_for_key()`
+    pub fn histogram_buckets<I: IntoIterator<Item = f64>>(mut self, upper_bounds: I) -> Self {
+        self.histogram_bounds = Some(upper_bounds.into_iter().collect());
+        self
+    }
+
+    /// Retries failed flushes up to `retries` times with exponential backoff before dropping the
+    /// queued metrics, none by default
+    ///
+    /// Recording isn't blocked while retrying but metrics may be dropped if the channel fills up
+    pub fn flush_retries(mut self, retries: u32) -> Self {
+        self.flush_retries = retries;
+        self
+    }
+
+    /// Sets delay before first flush retry, doubling each retry, 100ms by default
+    pub fn flush_retry_delay(mut self, delay: Duration) -> Self {
+        self.flush_retry_delay = Some(delay);
+        self
+    }
+
+    /// Caps total time spent retrying a flush, 5s by default
+    pub fn flush_retry_timeout(mut self, timeout: Duration) -> Self {
+        self.flush_retry_timeout = Some(timeout);
+        self
+    }
+
+    /// Stores with each sample how many times its key was recorded since the key's previous stored
+    /// sample, telling apart e.g. a gauge set once from one set many times that deadbands or
+    /// histogram reducers coalesced, see `MetricsDb::metrics_with_call_counts_for_key()`
+    pub fn record_call_counts(mut self, record: bool) -> Self {
+        self.record_call_counts = record;
+        self
+    }
+
+    /// Caps number of distinct keys stored, once reached samples & registrations of new keys are
+    /// dropped (logging each newly dropped key) while existing keys keep recording
+    ///
+    /// Guards against key explosions, e.g. from request IDs ending up in key names
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    /// Parks worker once nothing has been recorded for `idle` & all queued metrics are flushed,
+    /// skipping flush, house keeping & checkpoint wakeups until the next event arrives
+    ///
+    /// Saves power on battery devices that are idle for long periods
+    pub fn park_when_idle(mut self, idle: Duration) -> Self {
+        self.park_when_idle = Some(idle);
+        self
+    }
+
+    /// Ignores registrations of keys already registered as the same type since the exporter was
+    /// built, avoiding a database write each time a macro call site registers (on by default)
+    ///
+    /// Describing keys always updates the database
+    pub fn dedup_registrations(mut self, dedup: bool) -> Self {
+        self.dedup_registrations = dedup;
+        self
+    }
+
+    /// Records the running total of metrics dropped because the worker's channel was full under
+    /// `key` at each flush where it changed, see `SqliteExporter::dropped_count()`
+    pub fn dropped_count_key<K: Into<String>>(mut self, key: K) -> Self {
+        self.dropped_count_key = Some(key.into());
+        self
+    }
+
+    /// Sets how often the worker checkpoints the WAL alongside house keeping, truncating the `-wal`
+    /// file that can otherwise grow large between SQLite's automatic checkpoints
+    ///
+    /// Only has an effect if the database is in WAL mode, see `SqliteExporter::checkpoint()`
+    pub fn checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Vacuums the database after each periodic house keeping, shrinking the file by what was
+    /// deleted at the cost of rewriting it, off by default. See `SqliteExporter::vacuum()`.
+    pub fn vacuum_after_housekeeping(mut self, enabled: bool) -> Self {
+        self.vacuum_after_housekeeping = enabled;
+        self
+    }
+
+    /// Sets whether the database uses WAL journal mode, letting `MetricsDb` readers query while
+    /// the exporter writes without either blocking the other. Persists in the file.
+    ///
+    /// On by default as it is for `MetricsDb::new()`, disable for file systems without shared
+    /// memory support (e.g. network shares), switching the file back to a rollback journal.
+    pub fn wal_mode(mut self, enabled: bool) -> Self {
+        self.wal_mode = enabled;
+        self
+    }
+
+    /// Sets how long the exporter waits for another connection's lock (e.g. a reader's or a
+    /// `VACUUM`) before a write fails with "database is locked", 5s by default
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    /// Logs a warning at startup if existing database is over `bytes`, which usually means house
+    /// keeping has stopped working
+    pub fn warn_if_larger_than(mut self, bytes: u64) -> Self {
+        self.warn_size = Some(bytes);
+        self
+    }
+
+    /// Fails `build()` with `MetricsError::DatabaseTooLarge` if existing database is over `bytes`
+    pub fn fail_if_larger_than(mut self, bytes: u64) -> Self {
+        self.fail_size = Some(bytes);
+        self
+    }
+
+    /// Records into `<path>.tmp`, renamed to `path` by `SqliteExporter::shutdown()`, so a run
+    /// that doesn't complete never replaces the metrics file at `path`
+    pub fn promote_on_shutdown(mut self) -> Self {
+        self.promote_on_shutdown = true;
+        self
+    }
+
+    /// Limits how long dropping the exporter waits for the worker to flush & stop, leaving it to
+    /// finish in the background after that, unlimited by default
+    ///
+    /// See `SqliteExporter::shutdown_timeout()` for stopping with a timeout explicitly
+    pub fn drop_timeout(mut self, timeout: Duration) -> Self {
+        self.drop_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets worker thread's name, useful to tell exporters apart when several coexist
+    pub fn thread_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Sets worker thread's stack size in bytes, uses platform default otherwise
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Sets additional migrations applied after the built-in ones, for extending the schema
+    ///
+    /// Migration versions must not clash with the built-in migrations
+    pub fn extra_migrations(mut self, migrations: &'static EmbeddedMigrations) -> Self {
+        self.extra_migrations = Some(migrations);
+        self
+    }
+
+    /// Stamps database with name of app recording into it, see `MetricsDb::metadata()`
+    pub fn app_name<S: Into<String>>(mut self, app_name: S) -> Self {
+        self.metadata.push((METADATA_APP_NAME, app_name.into()));
+        self
+    }
+
+    /// Stamps database with version of app recording into it, see `MetricsDb::metadata()`
+    pub fn app_version<S: Into<String>>(mut self, app_version: S) -> Self {
+        self.metadata
+            .push((METADATA_APP_VERSION, app_version.into()));
+        self
+    }
+
+    /// Stamps database with host recording into it, see `MetricsDb::metadata()`
+    pub fn hostname<S: Into<String>>(mut self, hostname: S) -> Self {
+        self.metadata.push((METADATA_HOSTNAME, hostname.into()));
+        self
+    }
+
+    /// Sets observer called synchronously with every metric emitted, for asserting in tests
+    #[cfg(feature = "test-util")]
+    pub fn observer(mut self, observer: RecordingObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Creates the `SqliteExporter`, storing metrics in SQLite database at given path
+    ///
+    /// Fails with `MetricsError::InvalidArgument` if flush interval, channel capacity or flush
+    /// queue limit are zero or histogram bucket bounds aren't ascending, or
+    /// `MetricsError::InvalidRetentionPolicy` if house keeping settings are invalid
+    pub fn build<P: AsRef<Path>>(mut self, path: P) -> Result<SqliteExporter> {
+        if self.flush_interval.is_zero() {
+            return Err(MetricsError::InvalidArgument(
+                "flush interval must be greater than zero",
+            ));
+        }
+        if self.channel_capacity == 0 {
+            return Err(MetricsError::InvalidArgument(
+                "channel capacity must be greater than zero",
+            ));
+        }
+        if self.flush_queue_limit == 0 {
+            return Err(MetricsError::InvalidArgument(
+                "flush queue limit must be greater than zero",
+            ));
+        }
+        if let Some(bounds) = &self.histogram_bounds {
+            if bounds.iter().any(|bound| bound.is_nan())
+                || bounds.windows(2).any(|pair| pair[0] >= pair[1])
+            {
+                return Err(MetricsError::InvalidArgument(
+                    "histogram bucket bounds must be strictly ascending",
+                ));
+            }
+        }
+        let retention_policy = match self.housekeeping_period {
+            Some(period) => {
+                let policy = RetentionPolicy {
+                    retention: self.retention,
+                    record_limit: self.record_limit,
+                    ..RetentionPolicy::new(period)
+                };
+                policy.validate()?;
+                Some(policy)
+            }
+            None => None,
+        };
+        if let Some(normalizer) = self.key_normalizer.clone() {
+            self.key_deadbands = self
+                .key_deadbands
+                .into_iter()
+                .map(|(key, deadband)| (normalizer(&key), deadband))
+                .collect();
+            self.priority_keys = self
+                .priority_keys
+                .iter()
+                .map(|key| normalizer(key))
+                .collect();
+            self.delta_gauges = self
+                .delta_gauges
+                .iter()
+                .map(|key| normalizer(key))
+                .collect();
+            self.key_transforms = self
+                .key_transforms
+                .into_iter()
+                .map(|(key, transform)| (normalizer(&key), transform))
+                .collect();
+        }
+        let promote_to = if self.promote_on_shutdown {
+            let temp_path = path_with_suffix(path.as_ref(), ".tmp");
+            // start over if a previous run didn't complete
+            match std::fs::remove_file(&temp_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(MetricsError::PromoteError(e))
+                }
+                _ => {}
+            }
+            Some((temp_path, path.as_ref().to_path_buf()))
+        } else {
+            None
+        };
+        let db_path = match &promote_to {
+            Some((temp_path, _)) => temp_path.as_path(),
+            None => path.as_ref(),
+        };
+        let mut db = setup_db_with_migrations(db_path, self.extra_migrations, self.wal_mode)?;
+        if let Some(timeout) = self.busy_timeout {
+            set_busy_timeout(&mut db, timeout)?;
+        }
+        if self.warn_size.is_some() || self.fail_size.is_some() {
+            let size = total_bytes(&mut db)?;
+            if let Some(limit) = self.fail_size.filter(|limit| size > *limit) {
+                return Err(MetricsError::DatabaseTooLarge { size, limit });
+            }
+            if let Some(limit) = self.warn_size.filter(|limit| size > *limit) {
+                warn!(
+                    "Database is {} bytes, over limit of {} bytes, is house keeping working?",
+                    size, limit
+                );
+            }
+        }
+        for (name, value) in &self.metadata {
+            set_db_metadata(name, value, &mut db)?;
+        }
+        for (key, transform) in &self.key_transforms {
+            let key_id = MetricKey::key_by_name(key, &mut db)?.id;
+            MetricKey::set_attribute(key_id, "scale", &transform.scale.to_string(), &mut db)?;
+            MetricKey::set_attribute(key_id, "offset", &transform.offset.to_string(), &mut db)?;
+        }
+        if let Err(e) =
+            SqliteExporter::housekeeping(&mut db, self.retention, self.record_limit, true)
+        {
+            error!("Failed running house keeping: {:?}", e);
+        }
+        let mut state = InnerState::new(self.flush_interval, db);
+        state.set_housekeeping(retention_policy);
+        state.flush_queue_limit = self.flush_queue_limit;
+        state.initial_flush_samples = self.initial_flush_samples;
+        state.min_flush_interval = self.min_flush_interval;
+        state.gauge_deadband = self.gauge_deadband;
+        state.key_deadbands = self.key_deadbands;
+        state.priority_keys = self.priority_keys;
+        state.delta_gauges = self.delta_gauges;
+        state.key_normalizer = self.key_normalizer;
+        state.checkpoint_interval = self.checkpoint_interval;
+        state.vacuum_after_housekeeping = self.vacuum_after_housekeeping;
+        state.record_call_counts = self.record_call_counts;
+        state.max_keys = self.max_keys;
+        state.park_when_idle = self.park_when_idle;
+        state.dedup_registrations = self.dedup_registrations;
+        state.dropped_count_key = self.dropped_count_key;
+        let dropped = state.dropped.clone();
+        state.raw_mode = self.raw_mode;
+        state.require_gauge_baseline = self.require_gauge_baseline;
+        state.histogram_reducer = self.histogram_reducer;
+        state.histogram_bounds = self.histogram_bounds;
+        #[cfg(feature = "iso_timestamps")]
+        {
+            state.iso_timestamps = self.iso_timestamps;
+        }
+        state.key_transforms = self.key_transforms;
+        state.flush_retries = self.flush_retries;
+        if let Some(delay) = self.flush_retry_delay {
+            state.flush_retry_delay = delay;
+        }
+        if let Some(timeout) = self.flush_retry_timeout {
+            state.flush_retry_timeout = timeout;
+        }
+        let (sender, receiver) = std::sync::mpsc::sync_channel(self.channel_capacity);
+        let mut thread_builder = thread::Builder::new().name(self.thread_name);
+        if let Some(stack_size) = self.stack_size {
+            thread_builder = thread_builder.stack_size(stack_size);
+        }
+        let last_error = Arc::new(Mutex::new(None));
+        let thread = run_worker(state, receiver, thread_builder, last_error.clone())?;
+        Ok(SqliteExporter {
+            thread: Some(thread),
+            sender,
+            #[cfg(feature = "test-util")]
+            observer: self.observer,
+            promote_to,
+            last_error,
+            dropped,
+            drop_timeout: self.drop_timeout,
+        })
+    }
+}
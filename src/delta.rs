@@ -0,0 +1,125 @@
+//! Prototype delta encoding of a key's values, for evaluating at-rest size savings
+//!
+//! Values are quantized to multiples of `scale` & stored as integer deltas from the previous
+//! reconstructed value, with a full `f64` keyframe every `keyframe_interval` samples so a series
+//! can be decoded from any keyframe. SQLite stores small integers in 0-2 bytes versus 8 for REAL,
+//! so smooth signals shrink substantially at the cost of decoding on read.
+//!
+//! This is not yet used for storage, see the `delta_encoding` example for size vs decode cost
+//! against an existing database.
+
+/// Single encoded sample
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EncodedValue {
+    /// Full value, starting a new run of deltas
+    Keyframe(f64),
+    /// Difference from previous value in multiples of `DeltaEncoding::scale`
+    Delta(i64),
+}
+
+impl EncodedValue {
+    /// Bytes SQLite uses to store this value's column data (excluding record header)
+    pub fn stored_size(&self) -> usize {
+        match self {
+            EncodedValue::Keyframe(_) => 8,
+            EncodedValue::Delta(delta) => match *delta {
+                0 | 1 => 0,
+                -0x80..=0x7f => 1,
+                -0x8000..=0x7fff => 2,
+                -0x80_0000..=0x7f_ffff => 3,
+                -0x8000_0000..=0x7fff_ffff => 4,
+                -0x8000_0000_0000..=0x7fff_ffff_ffff => 6,
+                _ => 8,
+            },
+        }
+    }
+}
+
+/// Delta encoding settings
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DeltaEncoding {
+    /// Quantization step, decoded values are within `scale / 2` of the originals
+    pub scale: f64,
+    /// Number of samples between keyframes
+    pub keyframe_interval: usize,
+}
+
+impl DeltaEncoding {
+    /// Creates encoding quantizing to `scale` with a keyframe every `keyframe_interval` samples
+    pub fn new(scale: f64, keyframe_interval: usize) -> Self {
+        DeltaEncoding {
+            scale,
+            keyframe_interval: keyframe_interval.max(1),
+        }
+    }
+
+    /// Encodes values in recorded order
+    pub fn encode(&self, values: &[f64]) -> Vec<EncodedValue> {
+        let mut encoded = Vec::with_capacity(values.len());
+        let mut previous = 0.0;
+        for (i, value) in values.iter().copied().enumerate() {
+            let steps = ((value - previous) / self.scale).round();
+            // non-finite values & deltas too large for i64 fall back to keyframes
+            if i % self.keyframe_interval == 0
+                || !steps.is_finite()
+                || steps.abs() >= i64::MAX as f64
+            {
+                encoded.push(EncodedValue::Keyframe(value));
+                previous = value;
+            } else {
+                let delta = steps as i64;
+                encoded.push(EncodedValue::Delta(delta));
+                // tracks reconstructed value rather than original so error doesn't accumulate
+                previous += delta as f64 * self.scale;
+            }
+        }
+        encoded
+    }
+
+    /// Decodes values produced by `encode()`
+    pub fn decode(&self, encoded: &[EncodedValue]) -> Vec<f64> {
+        let mut previous = 0.0;
+        encoded
+            .iter()
+            .map(|value| {
+                previous = match value {
+                    EncodedValue::Keyframe(value) => *value,
+                    EncodedValue::Delta(delta) => previous + *delta as f64 * self.scale,
+                };
+                previous
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let encoding = DeltaEncoding::new(0.01, 4);
+        let values: Vec<f64> = (0..10)
+            .map(|i| 20.0 + (i as f64 * 0.3).sin())
+            .chain([f64::NAN, 1e300, 5.0])
+            .collect();
+        let encoded = encoding.encode(&values);
+        assert_eq!(encoded[0], EncodedValue::Keyframe(values[0]));
+        assert!(matches!(encoded[1], EncodedValue::Delta(_)));
+        assert_eq!(encoded[4], EncodedValue::Keyframe(values[4]));
+        assert!(encoded[10..12]
+            .iter()
+            .all(|v| matches!(v, EncodedValue::Keyframe(_))));
+        let decoded = encoding.decode(&encoded);
+        for (original, decoded) in values.iter().zip(&decoded) {
+            assert!(
+                (original.is_nan() && decoded.is_nan()) || (original - decoded).abs() <= 0.005,
+                "{} decoded as {}",
+                original,
+                decoded
+            );
+        }
+        let size: usize = encoded.iter().map(EncodedValue::stored_size).sum();
+        assert!(size < values.len() * 8);
+    }
+}
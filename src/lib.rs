@@ -8,17 +8,20 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate log;
 
+use diesel::migration::{Migration, MigrationSource};
 use diesel::prelude::*;
+use diesel::sqlite::Sqlite;
 use diesel::{insert_into, sql_query};
 
 use metrics::{GaugeValue, Key, KeyName, SetRecorderError, SharedString, Unit};
 
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::{
-    collections::{HashMap, VecDeque},
-    path::Path,
-    sync::mpsc::{Receiver, RecvTimeoutError, SyncSender},
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError},
     thread::{self, JoinHandle},
     time::{Duration, Instant, SystemTime},
 };
@@ -26,7 +29,24 @@ use thiserror::Error;
 
 /// Max number of items allowed in worker's queue before flushing regardless of flush duration
 const FLUSH_QUEUE_LIMIT: usize = 1000;
+/// Max bound parameters per statement in SQLite before 3.32, which system libraries may still be
+const SQLITE_MAX_VARIABLES: usize = 999;
+/// Columns bound per inserted sample, including `timestamp_text`
+const SAMPLE_COLUMNS: usize = 5;
+/// Max number of samples inserted per statement, keeping bound parameters under SQLite's limit
+const INSERT_CHUNK_LIMIT: usize = SQLITE_MAX_VARIABLES / SAMPLE_COLUMNS;
+/// Default capacity of the channel between recorder & worker, see `channel_capacity`
 const BACKGROUND_CHANNEL_LIMIT: usize = 8000;
+/// Default time a connection waits for another to release its lock before erroring
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default delay before first flush retry, doubling each retry
+const DEFAULT_FLUSH_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Default max time spent retrying a flush
+const DEFAULT_FLUSH_RETRY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often stopping with a timeout checks whether the worker finished
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Largest counter value stored exactly, beyond this `f64` loses precision
+const MAX_EXACT_COUNTER: u64 = 1 << 53;
 
 /// Error type for any db/vitals related errors
 #[derive(Debug, Error)]
@@ -44,7 +64,7 @@ pub enum MetricsError {
     #[error("Invalid database path")]
     InvalidDatabasePath,
     /// IO Error with reader/writer
-    #[cfg(feature = "csv")]
+    #[cfg(any(feature = "csv", feature = "export_jsonl", feature = "export_json"))]
     #[error("IO Error: {0}")]
     IoError(#[from] std::io::Error),
     /// Error writing CSV
@@ -57,245 +77,1172 @@ pub enum MetricsError {
     /// Given metric key name wasn't found in the DB
     #[error("Metric key {0} not found in database")]
     KeyNotFound(String),
+    /// Given metric key was only recorded with labels, so has no single series to query, see
+    /// `MetricsDb::label_sets_for_key()`
+    #[error("Metric key {0} only has labeled series, query them by label set")]
+    LabeledKey(String),
+    /// Given metric key exists but has no samples stored
+    #[error("Metric key {0} has no samples in database")]
+    NoMetricsForKey(String),
+    /// Error (de)serializing JSON
+    #[cfg(feature = "serde_json")]
+    #[error("JSON Error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    /// Failed to spawn worker thread
+    #[error("Failed to spawn worker thread: {0}")]
+    WorkerSpawnError(std::io::Error),
+    /// Retention policy given has nonsensical settings
+    #[error("Invalid retention policy: {0}")]
+    InvalidRetentionPolicy(&'static str),
+    /// Given metric key was recorded without storing its type
+    #[error("Metric key {0} has no type stored")]
+    UnknownKind(String),
+    /// Worker thread panicked while stopping
+    #[error("Worker thread panicked")]
+    WorkerPanicked,
+    /// Worker thread has already stopped so can't handle the request
+    #[error("Worker thread stopped")]
+    WorkerStopped,
+    /// Worker thread didn't finish flushing & stop within given time
+    #[error("Worker thread didn't stop within {0:?}")]
+    ShutdownTimeout(Duration),
+    /// Failed to rename temporary database into place
+    #[error("Failed to promote temporary database: {0}")]
+    PromoteError(std::io::Error),
+    /// Temporary database wasn't renamed into place as the worker's final flush failed
+    #[error("Not promoting temporary database, final flush failed: {0}")]
+    PromoteAborted(String),
+    /// Existing database exceeds size limit set with `SqliteExporterBuilder::fail_if_larger_than()`
+    #[error("Database is {size} bytes, over limit of {limit} bytes")]
+    DatabaseTooLarge {
+        /// Size of database in bytes
+        size: u64,
+        /// Configured limit in bytes
+        limit: u64,
+    },
+    /// Argument given is out of range, e.g. a zero step
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(&'static str),
+    /// Stored unit can't be converted to the requested one, e.g. bytes to seconds
+    #[error("Can't convert from unit '{from}' to '{to}'")]
+    IncompatibleUnits {
+        /// Unit stored for key, empty if none
+        from: String,
+        /// Unit requested
+        to: String,
+    },
 }
 /// Metrics result type
 pub type Result<T, E = MetricsError> = std::result::Result<T, E>;
+/// Function mapping key names to a canonical form, e.g. `http.requests` to `http_requests`
+type KeyNormalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
 
+mod builder;
+#[cfg(feature = "delta_encoding")]
+mod delta;
 mod metrics_db;
 mod models;
+mod multi_db;
+#[cfg(feature = "test-util")]
+mod observer;
 mod recorder;
+mod retention;
 mod schema;
 
+use crate::models::KeyIdCache;
 use crate::recorder::Handle;
-pub use metrics_db::{MetricsDb, Session};
-pub use models::{Metric, MetricKey, NewMetric};
+pub use builder::{Deadband, HistogramReducer, SqliteExporterBuilder, ValueTransform};
+#[cfg(feature = "delta_encoding")]
+pub use delta::{DeltaEncoding, EncodedValue};
+#[cfg(feature = "export_csv")]
+pub use metrics_db::CsvExportOptions;
+#[cfg(feature = "import_csv")]
+pub use metrics_db::ImportStats;
+pub use metrics_db::{
+    AlignedSeries, BucketAgg, DiffPoint, HealthReport, JoinedMetric, MetricsDb, MetricsDbHandle,
+    OnConflict, Session, SessionGap, SessionThresholds,
+};
+#[cfg(feature = "histogram_summary")]
+pub use models::HistogramSummary;
+pub use models::{
+    ArchivedMetric, HistogramBucket, Metric, MetricKey, MetricKind, NewMetric, RecordedEvent,
+    Timestamp, Value,
+};
+pub use multi_db::{MultiDbReader, SourcedSample};
+#[cfg(feature = "test-util")]
+pub use observer::RecordingObserver;
+pub use retention::{AdaptiveHousekeeping, ArchivePolicy, ArchiveResolution, RetentionPolicy};
 
 pub(crate) const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 fn setup_db<P: AsRef<Path>>(path: P) -> Result<SqliteConnection> {
+    setup_db_with_migrations(path, None, true)
+}
+/// Sets up database like `setup_db()`, applying given migrations after the built-in ones, in WAL
+/// journal mode unless `wal_mode` is false
+fn setup_db_with_migrations<P: AsRef<Path>>(
+    path: P,
+    extra_migrations: Option<&EmbeddedMigrations>,
+    wal_mode: bool,
+) -> Result<SqliteConnection> {
     let url = path
         .as_ref()
         .to_str()
         .ok_or(MetricsError::InvalidDatabasePath)?;
     let mut db = SqliteConnection::establish(url)?;
+    set_busy_timeout(&mut db, DEFAULT_BUSY_TIMEOUT)?;
+    // lets readers & the writer work concurrently, persisted in the file
+    match wal_mode {
+        true => sql_query("PRAGMA journal_mode = WAL").execute(&mut db)?,
+        false => sql_query("PRAGMA journal_mode = DELETE").execute(&mut db)?,
+    };
     db.run_pending_migrations(MIGRATIONS)
-        .map_err(|e| MetricsError::MigrationError(e))?;
+        .map_err(MetricsError::MigrationError)?;
+    if let Some(extra_migrations) = extra_migrations {
+        db.run_pending_migrations(BorrowedMigrations(extra_migrations))
+            .map_err(MetricsError::MigrationError)?;
+    }
+    // set after migrations so upgrading a database with orphaned samples doesn't fail, those are
+    // kept & can be found with `MetricsDb::find_orphan_samples()`
+    sql_query("PRAGMA foreign_keys = ON").execute(&mut db)?;
 
     Ok(db)
 }
-enum RegisterType {
-    Counter,
-    Gauge,
-    Histogram,
+/// Has connection wait up to `timeout` for other connections' locks instead of erroring with
+/// "database is locked" immediately
+fn set_busy_timeout(db: &mut SqliteConnection, timeout: Duration) -> Result<()> {
+    sql_query(format!("PRAGMA busy_timeout = {}", timeout.as_millis())).execute(db)?;
+    Ok(())
+}
+/// Lets migrations be run from a reference, `EmbeddedMigrations` being neither `Clone` nor `Copy`
+struct BorrowedMigrations<'a>(&'a EmbeddedMigrations);
+impl MigrationSource<Sqlite> for BorrowedMigrations<'_> {
+    fn migrations(&self) -> diesel::migration::Result<Vec<Box<dyn Migration<Sqlite>>>> {
+        MigrationSource::<Sqlite>::migrations(self.0)
+    }
 }
-
 enum Event {
     Stop,
-    DescribeKey(RegisterType, KeyName, Option<Unit>, SharedString),
-    RegisterKey(RegisterType, Key, Arc<Handle>),
+    DescribeKey(MetricKind, KeyName, Option<Unit>, SharedString),
+    RegisterKey(MetricKind, Key, Arc<Handle>),
     IncrementCounter(Duration, Key, u64),
     AbsoluteCounter(Duration, Key, u64),
     UpdateGauge(Duration, Key, GaugeValue),
     UpdateHistogram(Duration, Key, f64),
-    SetHousekeeping {
-        retention_period: Option<Duration>,
-        housekeeping_period: Option<Duration>,
-        record_limit: Option<usize>,
-    },
+    #[cfg(feature = "histogram_summary")]
+    RecordHistogramSummary(Duration, String, String),
+    RecordStateChange(Duration, String, String),
+    SetHousekeeping(Option<RetentionPolicy>),
+    Snapshot(SyncSender<HashMap<String, f64>>),
+    Checkpoint(SyncSender<Result<()>>),
+    Vacuum(SyncSender<Result<()>>),
 }
 
 /// Exports metrics by storing them in a SQLite database at a periodic interval
 pub struct SqliteExporter {
     thread: Option<JoinHandle<()>>,
     sender: SyncSender<Event>,
+    #[cfg(feature = "test-util")]
+    observer: Option<RecordingObserver>,
+    promote_to: Option<(PathBuf, PathBuf)>,
+    last_error: Arc<Mutex<Option<MetricsError>>>,
+    dropped: Arc<AtomicU64>,
+    drop_timeout: Option<Duration>,
+}
+/// Histogram observations reduced since last flush
+struct ReducedHistogram {
+    timestamp: Duration,
+    value: f64,
+    count: u64,
 }
 struct InnerState {
     db: SqliteConnection,
     last_housekeeping: Instant,
-    housekeeping: Option<Duration>,
-    retention: Option<Duration>,
-    record_limit: Option<usize>,
+    retention_policy: Option<RetentionPolicy>,
+    housekeeping_interval: Duration,
+    housekept_bytes: u64,
     flush_duration: Duration,
     last_flush: Instant,
+    min_flush_interval: Duration,
+    flush_retries: u32,
+    flush_retry_delay: Duration,
+    flush_retry_timeout: Duration,
+    initial_flush_samples: Option<usize>,
+    initial_flush_done: bool,
+    gauge_deadband: Option<Deadband>,
+    key_deadbands: HashMap<String, Deadband>,
+    priority_keys: HashSet<String>,
+    delta_gauges: HashSet<String>,
+    key_normalizer: Option<KeyNormalizer>,
+    checkpoint_interval: Option<Duration>,
+    last_checkpoint: Instant,
+    vacuum_after_housekeeping: bool,
+    record_call_counts: bool,
+    call_counts: HashMap<String, u64>,
+    max_keys: Option<usize>,
+    dropped_keys: HashSet<String>,
+    park_when_idle: Option<Duration>,
+    last_event: Instant,
+    dedup_registrations: bool,
+    registered_keys: HashMap<String, MetricKind>,
+    dropped: Arc<AtomicU64>,
+    dropped_count_key: Option<String>,
+    last_dropped: u64,
+    priority_queued: bool,
+    last_recorded: HashMap<Key, (Duration, f64)>,
     last_values: HashMap<Key, f64>,
     counters: HashMap<Key, u64>,
-    key_ids: HashMap<String, i64>,
+    lossy_counters: HashSet<String>,
+    raw_mode: bool,
+    require_gauge_baseline: bool,
+    #[cfg(feature = "iso_timestamps")]
+    iso_timestamps: bool,
+    histogram_reducer: Option<HistogramReducer>,
+    reduced_histograms: HashMap<Key, ReducedHistogram>,
+    histogram_bounds: Option<Vec<f64>>,
+    bucket_counts: HashMap<Key, Vec<u64>>,
+    bucket_queue: VecDeque<models::NewHistogramBucket>,
+    key_transforms: HashMap<String, ValueTransform>,
+    key_ids: KeyIdCache,
     queue: VecDeque<NewMetric>,
+    flush_queue_limit: usize,
+    event_queue: VecDeque<models::NewEvent>,
+    #[cfg(feature = "histogram_summary")]
+    summary_queue: VecDeque<models::NewHistogramSummary>,
 }
 impl InnerState {
     fn new(flush_duration: Duration, db: SqliteConnection) -> Self {
         InnerState {
             db,
             last_housekeeping: Instant::now(),
-            housekeeping: None,
-            retention: None,
-            record_limit: None,
+            retention_policy: None,
+            housekeeping_interval: Duration::ZERO,
+            housekept_bytes: 0,
             flush_duration,
             last_flush: Instant::now(),
+            min_flush_interval: Duration::ZERO,
+            flush_retries: 0,
+            flush_retry_delay: DEFAULT_FLUSH_RETRY_DELAY,
+            flush_retry_timeout: DEFAULT_FLUSH_RETRY_TIMEOUT,
+            initial_flush_samples: None,
+            initial_flush_done: false,
+            gauge_deadband: None,
+            key_deadbands: HashMap::new(),
+            priority_keys: HashSet::new(),
+            delta_gauges: HashSet::new(),
+            key_normalizer: None,
+            checkpoint_interval: None,
+            last_checkpoint: Instant::now(),
+            vacuum_after_housekeeping: false,
+            record_call_counts: false,
+            call_counts: HashMap::new(),
+            max_keys: None,
+            dropped_keys: HashSet::new(),
+            park_when_idle: None,
+            last_event: Instant::now(),
+            dedup_registrations: false,
+            registered_keys: HashMap::new(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            dropped_count_key: None,
+            last_dropped: 0,
+            priority_queued: false,
+            last_recorded: HashMap::new(),
             last_values: HashMap::new(),
             counters: HashMap::new(),
-            key_ids: HashMap::new(),
+            lossy_counters: HashSet::new(),
+            raw_mode: false,
+            require_gauge_baseline: false,
+            #[cfg(feature = "iso_timestamps")]
+            iso_timestamps: false,
+            histogram_reducer: None,
+            reduced_histograms: HashMap::new(),
+            histogram_bounds: None,
+            bucket_counts: HashMap::new(),
+            bucket_queue: VecDeque::new(),
+            key_transforms: HashMap::new(),
+            key_ids: KeyIdCache::default(),
             queue: VecDeque::with_capacity(FLUSH_QUEUE_LIMIT),
+            flush_queue_limit: FLUSH_QUEUE_LIMIT,
+            event_queue: VecDeque::new(),
+            #[cfg(feature = "histogram_summary")]
+            summary_queue: VecDeque::new(),
         }
     }
-    fn set_housekeeping(
-        &mut self,
-        retention: Option<Duration>,
-        housekeeping_duration: Option<Duration>,
-        record_limit: Option<usize>,
-    ) {
-        self.retention = retention;
-        self.housekeeping = housekeeping_duration;
+    fn set_housekeeping(&mut self, retention_policy: Option<RetentionPolicy>) {
+        if let Some(policy) = &retention_policy {
+            self.housekeeping_interval = policy.initial_interval();
+            if policy.adaptive.is_some() {
+                self.housekept_bytes = used_bytes(&mut self.db).unwrap_or(0);
+            }
+        }
+        self.retention_policy = retention_policy;
         self.last_housekeeping = Instant::now();
-        self.record_limit = record_limit;
     }
     fn should_housekeep(&self) -> bool {
-        match self.housekeeping {
-            Some(duration) => self.last_housekeeping.elapsed() > duration,
+        match &self.retention_policy {
+            Some(_) => self.last_housekeeping.elapsed() > self.housekeeping_interval,
+            None => false,
+        }
+    }
+    /// Counts a recording of given key towards its next stored sample's call count
+    fn count_call(&mut self, key: &str) {
+        if self.record_call_counts {
+            match self.call_counts.get_mut(key) {
+                Some(count) => *count += 1,
+                None => {
+                    self.call_counts.insert(key.to_string(), 1);
+                }
+            }
+        }
+    }
+    fn should_checkpoint(&self) -> bool {
+        match self.checkpoint_interval {
+            Some(interval) => self.last_checkpoint.elapsed() > interval,
             None => false,
         }
     }
+    fn checkpoint(&mut self) -> Result<(), diesel::result::Error> {
+        self.last_checkpoint = Instant::now();
+        checkpoint(&mut self.db)
+    }
+    fn vacuum(&mut self) -> Result<(), diesel::result::Error> {
+        debug!("Vacuuming database");
+        sql_query("VACUUM").execute(&mut self.db)?;
+        Ok(())
+    }
     fn housekeep(&mut self) -> Result<(), diesel::result::Error> {
-        SqliteExporter::housekeeping(&mut self.db, self.retention, self.record_limit, false);
+        let result = self.run_housekeeping_steps();
+        // even after failing, so a persistent error isn't retried on every pass of the worker
         self.last_housekeeping = Instant::now();
+        result
+    }
+    fn run_housekeeping_steps(&mut self) -> Result<(), diesel::result::Error> {
+        if let Some(policy) = &self.retention_policy {
+            if let Some(adaptive) = &policy.adaptive {
+                let grown = used_bytes(&mut self.db)?.saturating_sub(self.housekept_bytes);
+                self.housekeeping_interval =
+                    adaptive.next_interval(grown, self.last_housekeeping.elapsed());
+                debug!(
+                    "DB grew {} bytes, next house keeping in {}s",
+                    grown,
+                    self.housekeeping_interval.as_secs()
+                );
+            }
+            if let Some(archive) = &policy.archive {
+                match SystemTime::UNIX_EPOCH.elapsed() {
+                    Ok(now) => {
+                        let cutoff = now.saturating_sub(archive.after).as_secs_f64();
+                        match MetricsDb::archive_before(
+                            &mut self.db,
+                            cutoff,
+                            archive.resolution.seconds(),
+                        ) {
+                            Ok(archived) => debug!("Archived {} samples", archived),
+                            Err(e) => error!("Failed to archive old samples: {:?}", e),
+                        }
+                    }
+                    Err(e) => error!("System time error, skipping archiving: {}", e),
+                }
+            }
+            // log & carry on so one failing step doesn't hold back the rest
+            if let Err(e) = SqliteExporter::housekeeping(
+                &mut self.db,
+                policy.retention,
+                policy.record_limit,
+                false,
+            ) {
+                error!("Failed deleting old samples: {:?}", e);
+            }
+            if let Some(sessions) = policy.keep_sessions {
+                match MetricsDb::keep_last_sessions(&mut self.db, sessions) {
+                    Ok(removed) => debug!("Removed {} samples of older sessions", removed),
+                    Err(e) => error!("Failed to remove older sessions: {:?}", e),
+                }
+            }
+            if policy.adaptive.is_some() {
+                self.housekept_bytes = used_bytes(&mut self.db)?;
+            }
+            if self.vacuum_after_housekeeping {
+                self.vacuum()?;
+            }
+        }
         Ok(())
     }
+    /// Checks gauge value against deadband of its key, tracking last recorded value when it
+    /// should be recorded
+    fn within_deadband(&mut self, key: &Key, timestamp: Duration, value: f64) -> bool {
+        let deadband = match self
+            .key_deadbands
+            .get(key.name())
+            .or(self.gauge_deadband.as_ref())
+        {
+            Some(deadband) => *deadband,
+            None => return false,
+        };
+        match self.last_recorded.get_mut(key) {
+            Some((last_timestamp, last_value)) => {
+                let expired = match deadband.max_interval {
+                    Some(max_interval) => timestamp.saturating_sub(*last_timestamp) >= max_interval,
+                    None => false,
+                };
+                if !expired && (value - *last_value).abs() <= deadband.epsilon {
+                    return true;
+                }
+                *last_timestamp = timestamp;
+                *last_value = value;
+                false
+            }
+            None => {
+                self.last_recorded.insert(key.clone(), (timestamp, value));
+                false
+            }
+        }
+    }
+    /// Replaces key names in event with their normalized form, if a key normalizer is set
+    fn normalize_event(&self, event: Event) -> Event {
+        let normalizer = match &self.key_normalizer {
+            Some(normalizer) => normalizer,
+            None => return event,
+        };
+        let normalize = |key: Key| {
+            let (name, labels) = key.into_parts();
+            Key::from_parts(normalizer(name.as_str()), labels)
+        };
+        match event {
+            Event::DescribeKey(kind, name, unit, description) => Event::DescribeKey(
+                kind,
+                KeyName::from(normalizer(name.as_str())),
+                unit,
+                description,
+            ),
+            Event::RegisterKey(kind, key, handle) => {
+                Event::RegisterKey(kind, normalize(key), handle)
+            }
+            Event::IncrementCounter(timestamp, key, value) => {
+                Event::IncrementCounter(timestamp, normalize(key), value)
+            }
+            Event::AbsoluteCounter(timestamp, key, value) => {
+                Event::AbsoluteCounter(timestamp, normalize(key), value)
+            }
+            Event::UpdateGauge(timestamp, key, value) => {
+                Event::UpdateGauge(timestamp, normalize(key), value)
+            }
+            Event::UpdateHistogram(timestamp, key, value) => {
+                Event::UpdateHistogram(timestamp, normalize(key), value)
+            }
+            #[cfg(feature = "histogram_summary")]
+            Event::RecordHistogramSummary(timestamp, key, summary) => {
+                Event::RecordHistogramSummary(timestamp, normalizer(&key), summary)
+            }
+            event => event,
+        }
+    }
+    /// Applies gauge update to its last value, returning resulting value or None if it's relative
+    /// & `require_gauge_baseline` is set without an absolute value having been set yet
+    ///
+    /// Gauges set with `gauge_deltas()` return the change from the previous value instead
+    fn update_gauge(&mut self, key: &Key, value: GaugeValue) -> Option<f64> {
+        let last = match self.last_values.get(key) {
+            Some(last) => *last,
+            None if self.require_gauge_baseline && !matches!(value, GaugeValue::Absolute(_)) => {
+                return None;
+            }
+            None => 0.0,
+        };
+        let updated = value.update_value(last);
+        self.last_values.insert(key.clone(), updated);
+        if self.delta_gauges.contains(key.name()) {
+            Some(updated - last)
+        } else {
+            Some(updated)
+        }
+    }
     fn should_flush(&self) -> bool {
-        if self.last_flush.elapsed() > self.flush_duration {
+        let limit = match self.initial_flush_samples {
+            // flush early until first samples hit disk
+            Some(samples) if !self.initial_flush_done => samples,
+            _ => self.flush_queue_limit,
+        };
+        if self.priority_queued {
+            debug!("Flushing due to priority key");
+            true
+        } else if self.queue.len() + self.event_queue.len() >= limit {
+            // a full queue can't wait out the minimum interval
+            true
+        } else if self.last_flush.elapsed() < self.min_flush_interval {
+            false
+        } else if self.last_flush.elapsed() > self.flush_duration {
             debug!("Flushing due to {}s timeout", self.flush_duration.as_secs());
             true
         } else {
-            self.queue.len() >= FLUSH_QUEUE_LIMIT
+            false
         }
     }
     fn flush(&mut self) -> Result<(), diesel::result::Error> {
-        use crate::schema::metrics::dsl::metrics;
+        self.flush_resolving(OnConflict::Insert).map(|_| ())
+    }
+    /// Flushes queued metrics, resolving ones whose key & timestamp are already stored as given,
+    /// returning number of samples inserted or replaced
+    fn flush_resolving(&mut self, on_conflict: OnConflict) -> Result<usize, diesel::result::Error> {
         // trace!("Flushing {} records", self.queue.len());
-        let db = &mut self.db;
-        let queue = self.queue.drain(..);
-        db.transaction::<_, diesel::result::Error, _>(|db| {
-            for rec in queue {
-                insert_into(metrics).values(&rec).execute(db)?;
+        if !self.queue.is_empty() {
+            self.initial_flush_done = true;
+        }
+        // contiguous so samples can be inserted in chunks
+        let queue = &*self.queue.make_contiguous();
+        let event_queue = &self.event_queue;
+        let bucket_queue = &self.bucket_queue;
+        #[cfg(feature = "histogram_summary")]
+        let summary_queue = &self.summary_queue;
+        #[cfg(feature = "iso_timestamps")]
+        let iso_timestamps = self.iso_timestamps;
+        let insert_chunk = |db: &mut SqliteConnection, chunk: &[NewMetric]| {
+            #[cfg(feature = "iso_timestamps")]
+            if iso_timestamps {
+                return insert_samples_with_iso_timestamps(db, chunk);
+            }
+            insert_samples(db, chunk)
+        };
+        let written = self.db.transaction::<_, diesel::result::Error, _>(|db| {
+            let mut written = 0;
+            for chunk in queue.chunks(INSERT_CHUNK_LIMIT) {
+                if on_conflict == OnConflict::Insert {
+                    written += insert_chunk(db, chunk)?;
+                    continue;
+                }
+                // resolved one by one, only where asked for as samples may share a timestamp
+                for rec in chunk {
+                    written += match resolve_conflict(db, rec, on_conflict)? {
+                        Some(resolved) => resolved,
+                        None => insert_chunk(db, std::slice::from_ref(rec))?,
+                    };
+                }
+            }
+            for rec in bucket_queue {
+                insert_into(crate::schema::histogram_buckets::table)
+                    .values(rec)
+                    .execute(db)?;
             }
-            Ok(())
+            for rec in event_queue {
+                insert_into(crate::schema::events::table)
+                    .values(rec)
+                    .execute(db)?;
+            }
+            #[cfg(feature = "histogram_summary")]
+            for rec in summary_queue {
+                insert_into(crate::schema::histogram_summaries::table)
+                    .values(rec)
+                    .execute(db)?;
+            }
+            Ok(written)
         })?;
+        // only cleared once stored so failed flushes can be retried
+        self.queue.clear();
+        self.event_queue.clear();
+        self.bucket_queue.clear();
+        #[cfg(feature = "histogram_summary")]
+        self.summary_queue.clear();
         self.last_flush = Instant::now();
+        self.priority_queued = false;
+        Ok(written)
+    }
+    /// Returns how long worker waits for events before flushing, until `min_flush_interval` has
+    /// passed when within it so deferred metrics are flushed as soon as it's over
+    ///
+    /// None to wait indefinitely when idle long enough to park, see `park_when_idle`
+    fn recv_timeout(&self) -> Option<Duration> {
+        if let Some(idle) = self.park_when_idle {
+            if self.last_event.elapsed() >= idle && self.is_drained() {
+                return None;
+            }
+        }
+        let since_flush = self.last_flush.elapsed();
+        if since_flush < self.min_flush_interval {
+            Some(self.min_flush_interval - since_flush)
+        } else {
+            Some(self.flush_duration)
+        }
+    }
+    /// Checks nothing is waiting to be flushed
+    fn is_drained(&self) -> bool {
+        #[cfg(feature = "histogram_summary")]
+        if !self.summary_queue.is_empty() {
+            return false;
+        }
+        self.queue.is_empty()
+            && self.event_queue.is_empty()
+            && self.reduced_histograms.is_empty()
+            && self.bucket_counts.is_empty()
+            && self.bucket_queue.is_empty()
+    }
+    /// Reduces histogram observation into pending value for its key
+    fn reduce_histogram(
+        &mut self,
+        reducer: HistogramReducer,
+        timestamp: Duration,
+        key: Key,
+        value: f64,
+    ) {
+        let reduced = self
+            .reduced_histograms
+            .entry(key)
+            .or_insert(ReducedHistogram {
+                timestamp,
+                value,
+                count: 0,
+            });
+        if reduced.count > 0 {
+            reduced.value = match reducer {
+                HistogramReducer::Last => value,
+                HistogramReducer::Min => reduced.value.min(value),
+                HistogramReducer::Max => reduced.value.max(value),
+                // summed until queued
+                HistogramReducer::Mean => reduced.value + value,
+            };
+        }
+        reduced.timestamp = timestamp;
+        reduced.count += 1;
+    }
+    /// Queues reduced histogram values, one per key
+    fn queue_reduced_histograms(&mut self) -> Result<()> {
+        let reduced: Vec<_> = self.reduced_histograms.drain().collect();
+        for (key, reduced) in reduced {
+            let value = match self.histogram_reducer {
+                Some(HistogramReducer::Mean) => reduced.value / reduced.count as f64,
+                _ => reduced.value,
+            };
+            self.queue_metric(reduced.timestamp, key.name(), &key_labels(&key), value)?;
+        }
         Ok(())
     }
-    fn queue_metric(&mut self, timestamp: Duration, key: &str, value: f64) -> Result<()> {
-        let metric_key_id = match self.key_ids.get(key) {
-            Some(key) => *key,
-            None => {
-                debug!("Looking up {}", key);
-                let key_id = MetricKey::key_by_name(key, &mut self.db)?.id;
-                self.key_ids.insert(key.to_string(), key_id);
-                key_id
+    /// Counts histogram observation into its bucket if `histogram_bounds` are set
+    fn bucket_histogram(&mut self, key: &Key, value: f64) {
+        let bounds = match &self.histogram_bounds {
+            Some(bounds) if !value.is_nan() => bounds,
+            _ => return,
+        };
+        let bucket = bounds.partition_point(|bound| *bound < value);
+        let counts = self
+            .bucket_counts
+            .entry(key.clone())
+            .or_insert_with(|| vec![0; bounds.len() + 1]);
+        counts[bucket] += 1;
+    }
+    /// Queues bucket counts of the window since last flush, skipping empty buckets
+    fn queue_histogram_buckets(&mut self) -> Result<()> {
+        let bounds = match &self.histogram_bounds {
+            Some(bounds) => bounds.clone(),
+            None => return Ok(()),
+        };
+        let timestamp = SystemTime::UNIX_EPOCH
+            .elapsed()
+            .unwrap_or_default()
+            .as_secs_f64();
+        let counts: Vec<_> = self.bucket_counts.drain().collect();
+        for (key, counts) in counts {
+            let labels = key_labels(&key);
+            if !self.key_allowed(key.name(), &labels)? {
+                continue;
+            }
+            let metric_key_id = self
+                .key_ids
+                .id_for_labels(key.name(), &labels, &mut self.db)?;
+            for (bucket, count) in counts.into_iter().enumerate() {
+                if count == 0 {
+                    continue;
+                }
+                self.bucket_queue.push_back(models::NewHistogramBucket {
+                    timestamp,
+                    metric_key_id,
+                    upper_bound: bounds.get(bucket).copied().unwrap_or(f64::INFINITY),
+                    count: count as i64,
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Queues number of metrics dropped so far under `dropped_count_key` if it changed
+    fn queue_dropped_count(&mut self) -> Result<()> {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if let Some(key) = self.dropped_count_key.clone() {
+            if dropped != self.last_dropped {
+                let timestamp = SystemTime::UNIX_EPOCH.elapsed().unwrap_or_default();
+                self.queue_metric(timestamp, &key, "", dropped as f64)?;
             }
+        }
+        self.last_dropped = dropped;
+        Ok(())
+    }
+    /// Flushes, retrying failed transactions with exponential backoff up to `flush_retries` times
+    /// within `flush_retry_timeout`, dropping queued metrics once retries run out
+    fn flush_with_retry(&mut self) -> Result<(), diesel::result::Error> {
+        let started = Instant::now();
+        let mut delay = self.flush_retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.flush() {
+                Ok(()) => return Ok(()),
+                Err(e)
+                    if attempt < self.flush_retries
+                        && started.elapsed() + delay <= self.flush_retry_timeout =>
+                {
+                    warn!("Flush failed: {}, retrying in {}ms", e, delay.as_millis());
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!(
+                        "Flush failed, dropping {} metrics",
+                        self.queue.len() + self.event_queue.len()
+                    );
+                    self.queue.clear();
+                    self.event_queue.clear();
+                    self.bucket_queue.clear();
+                    #[cfg(feature = "histogram_summary")]
+                    self.summary_queue.clear();
+                    return Err(e);
+                }
+            }
+        }
+    }
+    /// Checks key against `max_keys`, existing keys are always allowed while new ones are only
+    /// allowed below the cap
+    fn key_allowed(&mut self, key: &str, labels: &str) -> Result<bool> {
+        let max_keys = match self.max_keys {
+            Some(max_keys) => max_keys,
+            None => return Ok(true),
         };
-        let metric = NewMetric {
+        if self.key_ids.contains(key, labels) {
+            return Ok(true);
+        }
+        let series = models::series_name(key, labels);
+        if self.dropped_keys.contains(series.as_ref()) {
+            return Ok(false);
+        }
+        if MetricKey::exists(key, labels, &mut self.db)?
+            || MetricKey::count(&mut self.db)? < max_keys
+        {
+            return Ok(true);
+        }
+        warn!(
+            "Dropping key {}, limit of {} keys reached ({} keys dropped)",
+            series,
+            max_keys,
+            self.dropped_keys.len() + 1
+        );
+        self.dropped_keys.insert(series.into_owned());
+        Ok(false)
+    }
+    /// Checks whether key was already registered as `kind`, noting it as registered when not
+    fn is_registered(&mut self, key: &str, kind: MetricKind) -> bool {
+        if !self.dedup_registrations {
+            return false;
+        }
+        match self.registered_keys.insert(key.to_string(), kind) {
+            Some(registered) => registered == kind,
+            None => false,
+        }
+    }
+    #[cfg(feature = "histogram_summary")]
+    fn queue_summary(&mut self, timestamp: Duration, key: &str, summary: String) -> Result<()> {
+        if !self.key_allowed(key, "")? {
+            return Ok(());
+        }
+        let metric_key_id = self.key_ids.id_for(key, &mut self.db)?;
+        self.summary_queue.push_back(models::NewHistogramSummary {
             timestamp: timestamp.as_secs_f64(),
             metric_key_id,
-            value: value as _,
+            summary,
+        });
+        Ok(())
+    }
+    /// Converts counter value for storage, warning once per key when it exceeds what `f64` can
+    /// represent exactly
+    fn counter_value(&mut self, key: &str, value: u64) -> f64 {
+        if value > MAX_EXACT_COUNTER && !self.lossy_counters.contains(key) {
+            warn!(
+                "Counter {} value {} exceeds 2^53, stored values lose precision",
+                key, value
+            );
+            self.lossy_counters.insert(key.to_string());
+        }
+        value as f64
+    }
+    /// Queues sample of the series of given key & labels (as from `models::labels_string()`)
+    fn queue_metric(
+        &mut self,
+        timestamp: Duration,
+        key: &str,
+        labels: &str,
+        value: f64,
+    ) -> Result<()> {
+        if !self.key_allowed(key, labels)? {
+            return Ok(());
+        }
+        let metric_key_id = self.key_ids.id_for_labels(key, labels, &mut self.db)?;
+        let value = match self.key_transforms.get(key) {
+            Some(transform) => transform.apply(value),
+            None => value,
+        };
+        if value.is_nan() {
+            // SQLite binds NaN as NULL, which would fail the whole flush
+            trace!("Dropping NaN sample of {}", key);
+            return Ok(());
+        }
+        let call_count = match self.record_call_counts {
+            true => Some(self.call_counts.remove(key).unwrap_or(0) as i64),
+            false => None,
+        };
+        let metric = NewMetric {
+            timestamp: Timestamp(timestamp.as_secs_f64()),
+            metric_key_id,
+            value: Value(value),
+            call_count,
         };
         self.queue.push_back(metric);
+        if self.priority_keys.contains(key) {
+            self.priority_queued = true;
+        }
         Ok(())
     }
 }
 
+/// Sends event built with `at` as time since the epoch to the worker, counting it in `dropped` if
+/// the channel is full or closed, or `at` is before the epoch
+fn send_counting_drops(
+    sender: &SyncSender<Event>,
+    dropped: &AtomicU64,
+    at: SystemTime,
+    event: impl FnOnce(Duration) -> Event,
+) {
+    match at.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(timestamp) => {
+            if let Err(_e) = sender.try_send(event(timestamp)) {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                #[cfg(feature = "log_dropped_metrics")]
+                error!(
+                    "Error sending metric to SQLite thread: {}, dropping metric",
+                    _e
+                );
+            }
+        }
+        Err(e) => {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            error!("Timestamp before UNIX epoch: {}, dropping metric", e);
+        }
+    }
+}
+
+/// Returns labels of key in stored form
+fn key_labels(key: &Key) -> String {
+    models::labels_string(key.labels().map(|label| (label.key(), label.value())))
+}
+
+/// Formats timestamp as UTC ISO-8601/RFC 3339 text, None if out of range
+#[cfg(feature = "iso_timestamps")]
+fn iso_timestamp(timestamp: f64) -> Option<String> {
+    use time::format_description::well_known::Rfc3339;
+    time::OffsetDateTime::from_unix_timestamp_nanos((timestamp * 1e9) as i128)
+        .ok()?
+        .format(&Rfc3339)
+        .ok()
+}
+
+/// Inserts chunk of samples in one statement, returning number of samples inserted
+fn insert_samples(
+    db: &mut SqliteConnection,
+    chunk: &[NewMetric],
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::metrics::dsl::metrics;
+    insert_into(metrics).values(chunk).execute(db)
+}
+/// Inserts chunk of samples like `insert_samples()`, storing each timestamp as RFC 3339 text too
+#[cfg(feature = "iso_timestamps")]
+fn insert_samples_with_iso_timestamps(
+    db: &mut SqliteConnection,
+    chunk: &[NewMetric],
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::metrics::dsl::*;
+    let rows: Vec<_> = chunk
+        .iter()
+        .map(|rec| (rec, timestamp_text.eq(iso_timestamp(rec.timestamp.0))))
+        .collect();
+    insert_into(metrics).values(rows).execute(db)
+}
+/// Resolves sample against already stored ones of its key & timestamp as given, returning number
+/// of samples replaced or None when it's still to be inserted
+///
+/// Keys & timestamps aren't unique, every stored sample matching it is replaced
+fn resolve_conflict(
+    db: &mut SqliteConnection,
+    rec: &NewMetric,
+    on_conflict: OnConflict,
+) -> Result<Option<usize>, diesel::result::Error> {
+    use crate::schema::metrics::dsl::*;
+    use diesel::dsl::{exists, select};
+    let existing = metrics
+        .filter(metric_key_id.eq(rec.metric_key_id))
+        .filter(timestamp.eq(rec.timestamp));
+    match on_conflict {
+        OnConflict::Insert => Ok(None),
+        OnConflict::Skip => {
+            let stored = select(exists(existing)).get_result::<bool>(db)?;
+            Ok(stored.then_some(0))
+        }
+        OnConflict::Replace => {
+            let replaced = diesel::update(existing)
+                .set((value.eq(rec.value), call_count.eq(rec.call_count)))
+                .execute(db)?;
+            Ok((replaced > 0).then_some(1))
+        }
+    }
+}
+
+/// Returns `path` with `suffix` appended to its file name, e.g. `metrics.db-wal`
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Result row of `PRAGMA wal_checkpoint`
+#[derive(QueryableByName)]
+struct CheckpointRow {
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    busy: i32,
+}
+/// Copies WAL contents into the database & truncates the WAL file, a no-op unless in WAL mode
+fn checkpoint(db: &mut SqliteConnection) -> Result<(), diesel::result::Error> {
+    let row = sql_query("PRAGMA wal_checkpoint(TRUNCATE)").get_result::<CheckpointRow>(db)?;
+    if row.busy != 0 {
+        warn!("WAL checkpoint incomplete, database busy with other connections");
+    }
+    Ok(())
+}
+#[derive(QueryableByName)]
+struct BytesRow {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    bytes: i64,
+}
+/// Returns bytes of database in use, excluding free pages
+fn used_bytes(db: &mut SqliteConnection) -> Result<u64, diesel::result::Error> {
+    let row = sql_query(
+        "SELECT (page_count - freelist_count) * page_size AS bytes \
+         FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+    )
+    .get_result::<BytesRow>(db)?;
+    Ok(row.bytes.max(0) as u64)
+}
+/// Returns total size of database in bytes, including free pages
+fn total_bytes(db: &mut SqliteConnection) -> Result<u64, diesel::result::Error> {
+    let row = sql_query(
+        "SELECT page_count * page_size AS bytes FROM pragma_page_count(), pragma_page_size()",
+    )
+    .get_result::<BytesRow>(db)?;
+    Ok(row.bytes.max(0) as u64)
+}
+
 fn run_worker(
-    db: SqliteConnection,
+    mut state: InnerState,
     receiver: Receiver<Event>,
-    flush_duration: Duration,
-) -> JoinHandle<()> {
-    thread::Builder::new()
-        .name("metrics-sqlite: worker".to_string())
+    thread_builder: thread::Builder,
+    last_error: Arc<Mutex<Option<MetricsError>>>,
+) -> Result<JoinHandle<()>> {
+    let flush_duration = state.flush_duration;
+    thread_builder
         .spawn(move || {
-            let mut state = InnerState::new(flush_duration, db);
             info!("SQLite worker started");
             loop {
-                let (should_flush, should_exit) = match receiver.recv_timeout(flush_duration) {
+                let mut vacuum_reply = None;
+                let event = match state.recv_timeout() {
+                    Some(timeout) => receiver.recv_timeout(timeout),
+                    None => {
+                        debug!("Worker idle, parking until next event");
+                        receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                    }
+                };
+                if event.is_ok() {
+                    state.last_event = Instant::now();
+                }
+                let event = event.map(|event| state.normalize_event(event));
+                let (should_flush, should_exit) = match event {
                     Ok(Event::Stop) => {
                         info!("Stopping SQLiteExporter worker, flushing & exiting");
                         (true, true)
                     }
-                    Ok(Event::SetHousekeeping {
-                        retention_period,
-                        housekeeping_period,
-                        record_limit,
-                    }) => {
-                        state.set_housekeeping(retention_period, housekeeping_period, record_limit);
+                    Ok(Event::SetHousekeeping(retention_policy)) => {
+                        state.set_housekeeping(retention_policy);
+                        (false, false)
+                    }
+                    Ok(Event::Snapshot(reply)) => {
+                        let values = state
+                            .counters
+                            .iter()
+                            .map(|(key, value)| (key.name().to_string(), *value as f64))
+                            .chain(
+                                state
+                                    .last_values
+                                    .iter()
+                                    .map(|(key, value)| (key.name().to_string(), *value)),
+                            )
+                            .collect();
+                        let _ = reply.send(values);
+                        (false, false)
+                    }
+                    Ok(Event::Checkpoint(reply)) => {
+                        let _ = reply.send(state.checkpoint().map_err(MetricsError::from));
                         (false, false)
                     }
-                    Ok(Event::DescribeKey(_key_type, key, unit, desc)) => {
+                    Ok(Event::Vacuum(reply)) => {
+                        // flush first so queued samples aren't held back behind the vacuum
+                        vacuum_reply = Some(reply);
+                        (true, false)
+                    }
+                    Ok(Event::DescribeKey(kind, key, unit, desc)) => {
                         info!("Describing key {:?}", key);
-                        if let Err(e) = MetricKey::create_or_update(
-                            key.as_str(),
-                            unit,
-                            Some(desc.as_ref()),
-                            &mut state.db,
-                        ) {
-                            error!("Failed to create key entry: {:?}", e);
+                        match state.key_allowed(key.as_str(), "") {
+                            Ok(true) => {
+                                if let Err(e) = MetricKey::create_or_update(
+                                    key.as_str(),
+                                    unit,
+                                    Some(desc.as_ref()),
+                                    &mut state.db,
+                                )
+                                .and_then(|_| {
+                                    MetricKey::set_kind(key.as_str(), "", kind, &mut state.db)
+                                }) {
+                                    error!("Failed to create key entry: {:?}", e);
+                                }
+                            }
+                            Ok(false) => {}
+                            Err(e) => error!("Failed to check key limit: {:?}", e),
                         }
                         (false, false)
                     }
-                    Ok(Event::RegisterKey(_key_type, _key, _handle)) => {
-                        // we currently don't do anything with register...
+                    Ok(Event::RegisterKey(kind, key, _handle)) => {
+                        let labels = key_labels(&key);
+                        match state.key_allowed(key.name(), &labels) {
+                            Ok(true)
+                                if state.is_registered(
+                                    &models::series_name(key.name(), &labels),
+                                    kind,
+                                ) => {}
+                            Ok(true) => {
+                                if let Err(e) =
+                                    MetricKey::set_kind(key.name(), &labels, kind, &mut state.db)
+                                {
+                                    error!("Failed to store key type: {:?}", e);
+                                }
+                            }
+                            Ok(false) => {}
+                            Err(e) => error!("Failed to check key limit: {:?}", e),
+                        }
                         (false, false)
                     }
                     Ok(Event::IncrementCounter(timestamp, key, value)) => {
+                        let labels = key_labels(&key);
                         let key_str = key.name().to_string();
-                        let entry = state.counters.entry(key).or_insert(0);
-                        let value = {
+                        state.count_call(&key_str);
+                        let total = if state.raw_mode {
+                            value
+                        } else {
+                            let entry = state.counters.entry(key).or_insert(0);
                             *entry += value;
                             *entry
                         };
-                        if let Err(e) = state.queue_metric(timestamp, &key_str, value as _) {
+                        let value = state.counter_value(&key_str, total);
+                        if let Err(e) = state.queue_metric(timestamp, &key_str, &labels, value) {
                             error!("Error queueing metric: {:?}", e);
                         }
 
                         (state.should_flush(), false)
                     }
                     Ok(Event::AbsoluteCounter(timestamp, key, value)) => {
+                        let labels = key_labels(&key);
                         let key_str = key.name().to_string();
-                        state.counters.insert(key, value);
-                        if let Err(e) = state.queue_metric(timestamp, &key_str, value as _) {
+                        state.count_call(&key_str);
+                        if !state.raw_mode {
+                            state.counters.insert(key, value);
+                        }
+                        let value = state.counter_value(&key_str, value);
+                        if let Err(e) = state.queue_metric(timestamp, &key_str, &labels, value) {
                             error!("Error queueing metric: {:?}", e);
                         }
                         (state.should_flush(), false)
                     }
                     Ok(Event::UpdateGauge(timestamp, key, value)) => {
+                        let labels = key_labels(&key);
                         let key_str = key.name().to_string();
-                        let entry = state.last_values.entry(key).or_insert(0.0);
-                        let value = match value {
-                            GaugeValue::Absolute(v) => {
-                                *entry = v;
-                                *entry
+                        state.count_call(&key_str);
+                        let value = if state.raw_mode {
+                            match value {
+                                GaugeValue::Absolute(v) | GaugeValue::Increment(v) => Some(v),
+                                GaugeValue::Decrement(v) => Some(-v),
                             }
-                            GaugeValue::Increment(v) => {
-                                *entry += v;
-                                *entry
+                        } else {
+                            state.update_gauge(&key, value)
+                        };
+                        match value {
+                            Some(value)
+                                if state.raw_mode
+                                    || state.delta_gauges.contains(&key_str)
+                                    || !state.within_deadband(&key, timestamp, value) =>
+                            {
+                                if let Err(e) =
+                                    state.queue_metric(timestamp, &key_str, &labels, value)
+                                {
+                                    error!("Error queueing metric: {:?}", e);
+                                }
                             }
-                            GaugeValue::Decrement(v) => {
-                                *entry -= v;
-                                *entry
+                            Some(_) => {}
+                            None => {
+                                debug!("Dropping relative update of {} before baseline", key_str)
                             }
-                        };
-                        if let Err(e) = state.queue_metric(timestamp, &key_str, value) {
-                            error!("Error queueing metric: {:?}", e);
                         }
                         (state.should_flush(), false)
                     }
                     Ok(Event::UpdateHistogram(timestamp, key, value)) => {
-                        let key_str = key.name().to_string();
-                        if let Err(e) = state.queue_metric(timestamp, &key_str, value) {
-                            error!("Error queueing metric: {:?}", e);
+                        state.count_call(key.name());
+                        state.bucket_histogram(&key, value);
+                        match state.histogram_reducer {
+                            Some(reducer) if !state.raw_mode => {
+                                state.reduce_histogram(reducer, timestamp, key, value);
+                            }
+                            _ => {
+                                let key_str = key.name().to_string();
+                                let labels = key_labels(&key);
+                                if let Err(e) =
+                                    state.queue_metric(timestamp, &key_str, &labels, value)
+                                {
+                                    error!("Error queueing metric: {:?}", e);
+                                }
+                            }
+                        }
+                        (state.should_flush(), false)
+                    }
+                    Ok(Event::RecordStateChange(timestamp, name, value)) => {
+                        state.event_queue.push_back(models::NewEvent {
+                            timestamp: timestamp.as_secs_f64(),
+                            name,
+                            value,
+                        });
+                        (state.should_flush(), false)
+                    }
+                    #[cfg(feature = "histogram_summary")]
+                    Ok(Event::RecordHistogramSummary(timestamp, key, summary)) => {
+                        if let Err(e) = state.queue_summary(timestamp, &key, summary) {
+                            error!("Error queueing histogram summary: {:?}", e);
                         }
-
                         (state.should_flush(), false)
                     }
                     Err(RecvTimeoutError::Timeout) => {
-                        debug!("Flushing due to {}s timeout", flush_duration.as_secs());
-                        (true, false)
+                        if state.last_flush.elapsed() < state.min_flush_interval {
+                            (false, false)
+                        } else {
+                            debug!("Flushing due to {}s timeout", flush_duration.as_secs());
+                            (true, false)
+                        }
                     }
                     Err(RecvTimeoutError::Disconnected) => {
                         warn!("SQLiteExporter channel disconnected, exiting worker");
@@ -303,21 +1250,51 @@ fn run_worker(
                     }
                 };
                 if should_flush {
-                    if let Err(e) = state.flush() {
+                    if let Err(e) = state.queue_reduced_histograms() {
+                        error!("Error queueing reduced histograms: {:?}", e);
+                    }
+                    if let Err(e) = state.queue_histogram_buckets() {
+                        error!("Error queueing histogram buckets: {:?}", e);
+                    }
+                    if let Err(e) = state.queue_dropped_count() {
+                        error!("Error queueing dropped metric count: {:?}", e);
+                    }
+                    let result = state.flush_with_retry();
+                    if let Err(e) = &result {
                         error!("Error flushing metrics: {}", e);
                     }
+                    if should_exit {
+                        *last_error.lock().unwrap_or_else(|e| e.into_inner()) =
+                            result.err().map(MetricsError::from);
+                    }
                 }
                 if state.should_housekeep() {
                     if let Err(e) = state.housekeep() {
                         error!("Failed running house keeping: {:?}", e);
                     }
                 }
+                if state.should_checkpoint() {
+                    if let Err(e) = state.checkpoint() {
+                        error!("Failed checkpointing WAL: {:?}", e);
+                    }
+                }
+                if let Some(reply) = vacuum_reply {
+                    let _ = reply.send(state.vacuum().map_err(MetricsError::from));
+                }
                 if should_exit {
+                    // fold the WAL into the file so it's complete without its `-wal` sidecar
+                    if let Err(e) = state.checkpoint() {
+                        error!("Failed checkpointing WAL: {:?}", e);
+                        let mut last_error = last_error.lock().unwrap_or_else(|e| e.into_inner());
+                        if last_error.is_none() {
+                            *last_error = Some(e.into());
+                        }
+                    }
                     break;
                 }
             }
         })
-        .unwrap()
+        .map_err(MetricsError::WorkerSpawnError)
 }
 
 impl SqliteExporter {
@@ -331,62 +1308,187 @@ impl SqliteExporter {
         keep_duration: Option<Duration>,
         path: P,
     ) -> Result<Self> {
-        let mut db = setup_db(path)?;
-        Self::housekeeping(&mut db, keep_duration, None, true);
-        let (sender, receiver) = std::sync::mpsc::sync_channel(BACKGROUND_CHANNEL_LIMIT);
-        let thread = run_worker(db, receiver, flush_interval);
-        let exporter = SqliteExporter {
-            thread: Some(thread),
-            sender,
-        };
-        Ok(exporter)
+        let mut builder = Self::builder().flush_interval(flush_interval);
+        if let Some(keep_duration) = keep_duration {
+            builder = builder.retention(keep_duration);
+        }
+        builder.build(path)
+    }
+
+    /// Creates a `SqliteExporterBuilder` for configuring options beyond what `new()` takes
+    pub fn builder() -> SqliteExporterBuilder {
+        SqliteExporterBuilder::new()
     }
 
     /// Sets optional periodic house keeping, None to disable (disabled by default)
+    ///
+    /// Returns an error if the given policy fails validation
     /// ## Notes
     /// Periodic house keeping can affect metric recording, causing some data to be dropped during house keeping.
     /// Record limit if set will cause anything over limit + 25% of limit to be removed
     pub fn set_periodic_housekeeping(
         &self,
-        periodic_duration: Option<Duration>,
-        retention: Option<Duration>,
-        record_limit: Option<usize>,
-    ) {
-        if let Err(e) = self.sender.send(Event::SetHousekeeping {
-            retention_period: retention,
-            housekeeping_period: periodic_duration,
-            record_limit,
-        }) {
+        retention_policy: Option<RetentionPolicy>,
+    ) -> Result<()> {
+        if let Some(policy) = &retention_policy {
+            policy.validate()?;
+        }
+        if let Err(e) = self.sender.send(Event::SetHousekeeping(retention_policy)) {
             error!("Failed to set house keeping settings: {:?}", e);
         }
+        Ok(())
+    }
+
+    /// Records an application computed histogram summary for given key, stored as JSON alongside
+    /// (and independent of) raw histogram observations
+    #[cfg(feature = "histogram_summary")]
+    pub fn record_histogram_summary(&self, key: &str, summary: &HistogramSummary) -> Result<()> {
+        let summary = serde_json::to_string(summary)?;
+        self.send_at(SystemTime::now(), |timestamp| {
+            Event::RecordHistogramSummary(timestamp, key.to_string(), summary)
+        });
+        Ok(())
+    }
+
+    /// Records a named text event, e.g. `("deploy_version", "1.4.2")`, stored alongside metrics
+    /// for marking state changes on charts, see `MetricsDb::events_in_range()`
+    pub fn record_event(&self, name: &str, value: &str) {
+        self.send_at(SystemTime::now(), |timestamp| {
+            Event::RecordStateChange(timestamp, name.to_string(), value.to_string())
+        });
+    }
+
+    /// Records a single value for given key without registering a handle, e.g. from scripts
+    ///
+    /// Counters are incremented by `value`, truncated to a whole non-negative number, gauges are
+    /// set to it & histograms record it
+    pub fn record_value(&self, key: &str, value: f64, kind: MetricKind) {
+        let key = Key::from_name(key.to_string());
+        let now = SystemTime::now();
+        match kind {
+            MetricKind::Counter => self.increment_counter_at(&key, value as u64, now),
+            MetricKind::Gauge => self.update_gauge_at(&key, GaugeValue::Absolute(value), now),
+            MetricKind::Histogram => self.record_histogram_at(&key, value, now),
+        }
+    }
+
+    /// Increments counter with a known event time instead of the current time, e.g. for delayed
+    /// sensor data. Counter totals accumulate in the order calls are made.
+    pub fn increment_counter_at(&self, key: &Key, value: u64, at: SystemTime) {
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.increment_counter(key, value);
+        }
+        self.send_at(at, |timestamp| {
+            Event::IncrementCounter(timestamp, key.clone(), value)
+        });
+    }
+
+    /// Sets counter to an absolute value with a known event time instead of the current time
+    pub fn absolute_counter_at(&self, key: &Key, value: u64, at: SystemTime) {
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.absolute_counter(key, value);
+        }
+        self.send_at(at, |timestamp| {
+            Event::AbsoluteCounter(timestamp, key.clone(), value)
+        });
     }
 
-    /// Run housekeeping.
+    /// Updates gauge with a known event time instead of the current time
+    pub fn update_gauge_at(&self, key: &Key, value: GaugeValue, at: SystemTime) {
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.update_gauge(key, value.clone());
+        }
+        self.send_at(at, |timestamp| {
+            Event::UpdateGauge(timestamp, key.clone(), value)
+        });
+    }
+
+    /// Records histogram value with a known event time instead of the current time
+    pub fn record_histogram_at(&self, key: &Key, value: f64, at: SystemTime) {
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.record_histogram(key, value);
+        }
+        self.send_at(at, |timestamp| {
+            Event::UpdateHistogram(timestamp, key.clone(), value)
+        });
+    }
+
+    /// Returns current total of every counter & value of every gauge by key name, as held by the
+    /// worker including values not yet flushed
     ///
-    /// Does nothing if None was given for keep_duration in `new()`
-    fn housekeeping(
+    /// Blocks until the worker handles the request, returning an empty map if it's stopped.
+    /// Values are before any `key_transform()`, & keys differing only by labels share an entry.
+    pub fn current_values(&self) -> HashMap<String, f64> {
+        let (reply, response) = std::sync::mpsc::sync_channel(1);
+        if self.sender.send(Event::Snapshot(reply)).is_err() {
+            error!("SQLite worker stopped, no values to snapshot");
+            return HashMap::new();
+        }
+        response.recv().unwrap_or_default()
+    }
+
+    /// Has the worker checkpoint the WAL, folding it back into the database & truncating the
+    /// `-wal` file, blocking until done. Does nothing unless the database is in WAL mode.
+    pub fn checkpoint(&self) -> Result<()> {
+        let (reply, response) = std::sync::mpsc::sync_channel(1);
+        self.sender
+            .send(Event::Checkpoint(reply))
+            .map_err(|_| MetricsError::WorkerStopped)?;
+        response.recv().map_err(|_| MetricsError::WorkerStopped)?
+    }
+
+    /// Has the worker flush queued samples then `VACUUM` the database, returning pages freed by
+    /// house keeping to the file system, blocking until done
+    ///
+    /// Rewrites the whole database so takes a while on large files, metrics emitted meanwhile
+    /// queue up in the channel & are dropped if it fills.
+    pub fn vacuum(&self) -> Result<()> {
+        let (reply, response) = std::sync::mpsc::sync_channel(1);
+        self.sender
+            .send(Event::Vacuum(reply))
+            .map_err(|_| MetricsError::WorkerStopped)?;
+        response.recv().map_err(|_| MetricsError::WorkerStopped)?
+    }
+
+    fn send_at<F: FnOnce(Duration) -> Event>(&self, at: SystemTime, event: F) {
+        send_counting_drops(&self.sender, &self.dropped, at, event);
+    }
+
+    /// Run housekeeping, deleting samples older than `keep_duration` & oldest samples over
+    /// `record_limit`, stopping at the first failed step
+    ///
+    /// Does nothing if neither is given
+    pub(crate) fn housekeeping(
         db: &mut SqliteConnection,
         keep_duration: Option<Duration>,
         record_limit: Option<usize>,
         vacuum: bool,
-    ) {
+    ) -> Result<(), diesel::result::Error> {
         use crate::schema::metrics::dsl::*;
         use diesel::dsl::count;
         if let Some(keep_duration) = keep_duration {
             match SystemTime::UNIX_EPOCH.elapsed() {
                 Ok(now) => {
-                    let cutoff = now - keep_duration;
+                    use crate::schema::{events, histogram_buckets, metrics_archive};
+                    let cutoff = now.saturating_sub(keep_duration).as_secs_f64();
                     trace!("Deleting data {}s old", keep_duration.as_secs());
-                    if let Err(e) =
-                        diesel::delete(metrics.filter(timestamp.le(cutoff.as_secs_f64())))
-                            .execute(db)
-                    {
-                        error!("Failed to remove old metrics data: {}", e);
-                    }
+                    diesel::delete(metrics.filter(timestamp.le(cutoff))).execute(db)?;
+                    diesel::delete(
+                        metrics_archive::table.filter(metrics_archive::timestamp.le(cutoff)),
+                    )
+                    .execute(db)?;
+                    diesel::delete(
+                        histogram_buckets::table.filter(histogram_buckets::timestamp.le(cutoff)),
+                    )
+                    .execute(db)?;
+                    diesel::delete(events::table.filter(events::timestamp.le(cutoff)))
+                        .execute(db)?;
                     if vacuum {
-                        if let Err(e) = sql_query("VACUUM").execute(db) {
-                            error!("Failed to vacuum SQLite DB: {:?}", e);
-                        }
+                        sql_query("VACUUM").execute(db)?;
                     }
                 }
                 Err(e) => {
@@ -399,28 +1501,128 @@ impl SqliteExporter {
         }
         if let Some(record_limit) = record_limit {
             trace!("Checking for records over {} limit", record_limit);
-            match metrics.select(count(id)).first::<i64>(db) {
-                Ok(records) => {
-                    let records = records as usize;
-                    if records > record_limit {
-                        let excess = records - record_limit + (record_limit / 4); // delete excess + 25% of limit
-                        trace!(
-                            "Exceeded limit! {} > {}, deleting {} oldest",
-                            records,
-                            record_limit,
-                            excess
-                        );
-                        let query = format!("DELETE FROM metrics WHERE id IN (SELECT id FROM metrics ORDER BY timestamp ASC LIMIT {});", excess);
-                        if let Err(e) = sql_query(query).execute(db) {
-                            error!("Failed to delete excessive records: {:?}", e);
+            let records = metrics.select(count(id)).first::<i64>(db)? as usize;
+            if records > record_limit {
+                let excess = records - record_limit + (record_limit / 4); // delete excess + 25% of limit
+                trace!(
+                    "Exceeded limit! {} > {}, deleting {} oldest",
+                    records,
+                    record_limit,
+                    excess
+                );
+                sql_query(
+                    "DELETE FROM metrics WHERE id IN \
+                     (SELECT id FROM metrics ORDER BY timestamp ASC LIMIT ?)",
+                )
+                .bind::<diesel::sql_types::BigInt, _>(excess as i64)
+                .execute(db)?;
+                // events don't count towards the limit, they're just kept no older than samples
+                let oldest = metrics
+                    .select(diesel::dsl::min(timestamp))
+                    .first::<Option<f64>>(db)?;
+                if let Some(oldest) = oldest {
+                    use crate::schema::events;
+                    diesel::delete(events::table.filter(events::timestamp.lt(oldest)))
+                        .execute(db)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops worker after flushing queued metrics, renaming temporary database into place if built
+    /// with `SqliteExporterBuilder::promote_on_shutdown()`
+    ///
+    /// Dropping the exporter also stops the worker but leaves a temporary database where it is, as
+    /// does a failed final flush, returning `MetricsError::PromoteAborted`
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop(None)?;
+        self.promote()
+    }
+
+    /// Stops worker like `shutdown()`, giving up with `MetricsError::ShutdownTimeout` if it
+    /// hasn't finished flushing within `timeout`, e.g. for services that must exit promptly
+    ///
+    /// On timeout the worker is left to finish in the background & nothing is promoted, neither is
+    /// anything after a failed final flush
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Result<()> {
+        self.stop(Some(timeout))?;
+        self.promote()
+    }
+
+    fn promote(&mut self) -> Result<()> {
+        if let Some((temp_path, path)) = self.promote_to.take() {
+            if let Some(e) = self.lock_last_error().as_ref() {
+                return Err(MetricsError::PromoteAborted(e.to_string()));
+            }
+            // worker checkpointed the WAL before closing, but sidecars stay while other connections
+            // are open, so move them along rather than strand them or leave stale ones at `path`
+            for suffix in ["-wal", "-shm"] {
+                let from = path_with_suffix(&temp_path, suffix);
+                let to = path_with_suffix(&path, suffix);
+                let result = match from.exists() {
+                    true => std::fs::rename(from, to),
+                    false => std::fs::remove_file(to),
+                };
+                match result {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(MetricsError::PromoteError(e))
+                    }
+                    _ => {}
+                }
+            }
+            std::fs::rename(temp_path, path).map_err(MetricsError::PromoteError)?;
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if let Some(thread) = self.thread.take() {
+            match timeout {
+                Some(timeout) => {
+                    let deadline = Instant::now() + timeout;
+                    // queue may be full of metrics, so wait for room without blocking past deadline
+                    while let Err(TrySendError::Full(_)) = self.sender.try_send(Event::Stop) {
+                        if Instant::now() >= deadline {
+                            return Err(MetricsError::ShutdownTimeout(timeout));
+                        }
+                        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                    }
+                    while !thread.is_finished() {
+                        if Instant::now() >= deadline {
+                            return Err(MetricsError::ShutdownTimeout(timeout));
                         }
+                        thread::sleep(SHUTDOWN_POLL_INTERVAL);
                     }
                 }
-                Err(e) => {
-                    error!("Failed to get record count: {:?}", e);
+                None => {
+                    let _ = self.sender.send(Event::Stop);
                 }
             }
+            if thread.join().is_err() {
+                *self.lock_last_error() = Some(MetricsError::WorkerPanicked);
+                return Err(MetricsError::WorkerPanicked);
+            }
         }
+        Ok(())
+    }
+
+    /// Returns shared slot the worker stores its final flush's error in when stopping, `None` if it
+    /// succeeded
+    ///
+    /// Keep a clone to check after dropping the exporter, as `Drop` can only log errors
+    pub fn last_error_slot(&self) -> Arc<Mutex<Option<MetricsError>>> {
+        self.last_error.clone()
+    }
+
+    /// Returns how many metrics, events & histogram summaries were dropped since building the
+    /// exporter, because the channel to the worker was full or the system time was invalid
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn lock_last_error(&self) -> std::sync::MutexGuard<'_, Option<MetricsError>> {
+        self.last_error.lock().unwrap_or_else(|e| e.into_inner())
     }
 
     /// Install recorder as `metrics` crate's Recorder
@@ -430,16 +1632,575 @@ impl SqliteExporter {
 }
 impl Drop for SqliteExporter {
     fn drop(&mut self) {
-        let _ = self.sender.send(Event::Stop);
-        let _ = self.thread.take().unwrap().join();
+        if let Err(e) = self.stop(self.drop_timeout) {
+            error!("Failed to stop worker: {:?}", e);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::SqliteExporter;
+    use crate::{
+        setup_db, used_bytes, Deadband, HistogramReducer, InnerState, MetricKind, MetricsDb,
+        MetricsError, SqliteExporter, Timestamp, Value, INSERT_CHUNK_LIMIT, MAX_EXACT_COUNTER,
+    };
+    use metrics::{GaugeValue, Key};
     use std::time::{Duration, Instant};
 
+    #[test]
+    fn test_deadband() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        state.gauge_deadband = Some(Deadband {
+            epsilon: 0.5,
+            max_interval: Some(Duration::from_secs(60)),
+        });
+        let key = Key::from_name("gauge");
+        let at = Duration::from_secs;
+        assert!(!state.within_deadband(&key, at(0), 1.0));
+        assert!(state.within_deadband(&key, at(1), 1.4));
+        assert!(!state.within_deadband(&key, at(2), 1.6));
+        assert!(state.within_deadband(&key, at(3), 1.2));
+        // proof of life sample once max interval passes
+        assert!(!state.within_deadband(&key, at(62), 1.6));
+    }
+
+    #[test]
+    fn test_priority_keys() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        state.priority_keys.insert("critical".to_string());
+        state
+            .queue_metric(Duration::from_secs(1), "noise", "", 1.0)
+            .unwrap();
+        assert!(!state.should_flush());
+        state
+            .queue_metric(Duration::from_secs(2), "critical", "", 1.0)
+            .unwrap();
+        assert!(state.should_flush());
+        state.flush().unwrap();
+        assert!(!state.should_flush());
+    }
+
+    #[test]
+    fn test_min_flush_interval() {
+        let mut state = InnerState::new(Duration::ZERO, setup_db(":memory:").unwrap());
+        state.min_flush_interval = Duration::from_secs(60);
+        state.priority_keys.insert("critical".to_string());
+        state
+            .queue_metric(Duration::from_secs(1), "noise", "", 1.0)
+            .unwrap();
+        assert!(!state.should_flush());
+        assert!(state.recv_timeout().unwrap() > Duration::from_secs(59));
+        state
+            .queue_metric(Duration::from_secs(2), "critical", "", 1.0)
+            .unwrap();
+        assert!(state.should_flush());
+        state.min_flush_interval = Duration::ZERO;
+        assert!(state.should_flush());
+        assert_eq!(state.recv_timeout(), Some(Duration::ZERO));
+        // queue limit overrides the minimum interval
+        state.flush().unwrap();
+        state.min_flush_interval = Duration::from_secs(60);
+        state.flush_queue_limit = 2;
+        state
+            .queue_metric(Duration::from_secs(3), "noise", "", 2.0)
+            .unwrap();
+        assert!(!state.should_flush());
+        state
+            .queue_metric(Duration::from_secs(4), "noise", "", 3.0)
+            .unwrap();
+        assert!(state.should_flush());
+    }
+
+    #[test]
+    fn test_flush_in_chunks() {
+        use diesel::prelude::*;
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        let total = INSERT_CHUNK_LIMIT * 2 + 1;
+        for i in 0..total {
+            state
+                .queue_metric(Duration::from_secs(i as u64), "a", "", i as f64)
+                .unwrap();
+        }
+        state.flush().unwrap();
+        assert!(state.queue.is_empty());
+        let stored: i64 = crate::schema::metrics::table
+            .count()
+            .get_result(&mut state.db)
+            .unwrap();
+        assert_eq!(stored, total as i64);
+    }
+
+    #[test]
+    fn test_park_when_idle() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        state.park_when_idle = Some(Duration::ZERO);
+        assert_eq!(state.recv_timeout(), None);
+        state
+            .queue_metric(Duration::from_secs(1), "a", "", 1.0)
+            .unwrap();
+        assert_eq!(state.recv_timeout(), Some(Duration::from_secs(5)));
+        state.flush().unwrap();
+        assert_eq!(state.recv_timeout(), None);
+    }
+
+    #[test]
+    fn test_require_gauge_baseline() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        let key = Key::from_name("connections");
+        assert_eq!(
+            state.update_gauge(&key, GaugeValue::Decrement(1.0)),
+            Some(-1.0)
+        );
+        state.last_values.clear();
+        state.require_gauge_baseline = true;
+        assert_eq!(state.update_gauge(&key, GaugeValue::Decrement(1.0)), None);
+        assert_eq!(
+            state.update_gauge(&key, GaugeValue::Absolute(5.0)),
+            Some(5.0)
+        );
+        assert_eq!(
+            state.update_gauge(&key, GaugeValue::Decrement(1.0)),
+            Some(4.0)
+        );
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        state.max_keys = Some(2);
+        let now = Duration::from_secs(1);
+        for key in ["a", "b", "c", "a"] {
+            state.queue_metric(now, key, "", 1.0).unwrap();
+        }
+        assert_eq!(state.queue.len(), 3);
+        assert!(state.dropped_keys.contains("c"));
+        assert_eq!(crate::models::MetricKey::count(&mut state.db).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_dedup_registrations() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        assert!(!state.is_registered("a", MetricKind::Counter));
+        state.dedup_registrations = true;
+        assert!(!state.is_registered("a", MetricKind::Counter));
+        assert!(state.is_registered("a", MetricKind::Counter));
+        assert!(!state.is_registered("a", MetricKind::Gauge));
+        assert!(!state.is_registered("b", MetricKind::Gauge));
+    }
+
+    #[test]
+    fn test_gauge_deltas() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        state.delta_gauges.insert("balance".to_string());
+        let key = Key::from_name("balance");
+        assert_eq!(
+            state.update_gauge(&key, GaugeValue::Increment(10.0)),
+            Some(10.0)
+        );
+        assert_eq!(
+            state.update_gauge(&key, GaugeValue::Decrement(3.0)),
+            Some(-3.0)
+        );
+        assert_eq!(
+            state.update_gauge(&key, GaugeValue::Absolute(20.0)),
+            Some(13.0)
+        );
+        assert_eq!(state.last_values[&key], 20.0);
+    }
+
+    #[test]
+    fn test_counter_value() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        assert_eq!(
+            state.counter_value("bytes", MAX_EXACT_COUNTER),
+            MAX_EXACT_COUNTER as f64
+        );
+        assert!(state.lossy_counters.is_empty());
+        // beyond 2^53 odd values round to the nearest representable even one
+        assert_eq!(
+            state.counter_value("bytes", MAX_EXACT_COUNTER + 1),
+            MAX_EXACT_COUNTER as f64
+        );
+        assert!(state.lossy_counters.contains("bytes"));
+        state.counter_value("bytes", u64::MAX);
+        assert_eq!(state.lossy_counters.len(), 1);
+        assert_eq!(state.counter_value("requests", 1), 1.0);
+        assert!(!state.lossy_counters.contains("requests"));
+    }
+
+    #[test]
+    fn test_flush_retry() {
+        use diesel::prelude::*;
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        state.flush_retries = 2;
+        state.flush_retry_delay = Duration::from_millis(10);
+        state
+            .queue_metric(Duration::from_secs(1), "a", "", 1.0)
+            .unwrap();
+        state.event_queue.push_back(crate::models::NewEvent {
+            timestamp: 1.0,
+            name: "mode".to_string(),
+            value: "idle".to_string(),
+        });
+        diesel::sql_query("DROP TABLE metrics")
+            .execute(&mut state.db)
+            .unwrap();
+        let start = Instant::now();
+        assert!(state.flush_with_retry().is_err());
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert!(state.queue.is_empty());
+        assert!(state.event_queue.is_empty());
+    }
+
+    #[test]
+    fn test_histogram_reducer() {
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        state.histogram_reducer = Some(HistogramReducer::Mean);
+        let key = Key::from_name("latency");
+        for (secs, value) in [(1, 2.0), (2, 6.0), (3, 4.0)] {
+            state.reduce_histogram(
+                HistogramReducer::Mean,
+                Duration::from_secs(secs),
+                key.clone(),
+                value,
+            );
+        }
+        state.queue_reduced_histograms().unwrap();
+        assert_eq!(state.queue.len(), 1);
+        assert_eq!(state.queue[0].timestamp, Timestamp(3.0));
+        assert_eq!(state.queue[0].value, Value(4.0));
+        assert!(state.reduced_histograms.is_empty());
+    }
+
+    #[test]
+    fn test_raw_mode() {
+        use metrics::Recorder;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("raw-mode.db");
+        let exporter = SqliteExporter::builder()
+            .raw_mode(true)
+            .gauge_deadband(Deadband {
+                epsilon: 10.0,
+                max_interval: None,
+            })
+            .histogram_reducer(HistogramReducer::Max)
+            .build(&path)
+            .unwrap();
+        let counter = exporter.register_counter(&Key::from_name("requests"));
+        let gauge = exporter.register_gauge(&Key::from_name("depth"));
+        let histogram = exporter.register_histogram(&Key::from_name("latency"));
+        counter.increment(2);
+        gauge.set(5.0);
+        histogram.record(1.0);
+        counter.increment(3);
+        gauge.increment(1.0);
+        histogram.record(4.0);
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let mut values = |key: &str| -> Vec<f64> {
+            db.metrics_for_key(key, None)
+                .unwrap()
+                .iter()
+                .map(|m| m.value.0)
+                .collect()
+        };
+        assert_eq!(values("requests"), vec![2.0, 3.0]);
+        assert_eq!(values("depth"), vec![5.0, 1.0]);
+        assert_eq!(values("latency"), vec![1.0, 4.0]);
+    }
+
+    #[cfg(feature = "iso_timestamps")]
+    #[test]
+    fn test_iso_timestamps() {
+        use crate::schema::metrics::dsl::*;
+        use diesel::prelude::*;
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(":memory:").unwrap());
+        state.iso_timestamps = true;
+        state
+            .queue_metric(Duration::from_millis(1_700_000_000_500), "a", "", 1.0)
+            .unwrap();
+        state.flush().unwrap();
+        let text = metrics
+            .select(timestamp_text)
+            .first::<Option<String>>(&mut state.db)
+            .unwrap();
+        assert_eq!(text.as_deref(), Some("2023-11-14T22:13:20.5Z"));
+    }
+
+    #[test]
+    fn test_last_error_slot() {
+        let exporter = SqliteExporter::new(Duration::from_secs(60), None, ":memory:").unwrap();
+        exporter.record_value("requests", 1.0, MetricKind::Counter);
+        let slot = exporter.last_error_slot();
+        drop(exporter);
+        assert!(slot.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shutdown-timeout.db");
+        let exporter = SqliteExporter::builder()
+            .promote_on_shutdown()
+            .build(&path)
+            .unwrap();
+        exporter.record_value("requests", 1.0, MetricKind::Counter);
+        // rendezvous reply nobody receives keeps the worker stuck sending it
+        let (reply, response) = std::sync::mpsc::sync_channel(0);
+        exporter.sender.send(crate::Event::Snapshot(reply)).unwrap();
+        assert!(matches!(
+            exporter.shutdown_timeout(Duration::from_millis(20)),
+            Err(MetricsError::ShutdownTimeout(_))
+        ));
+        drop(response);
+        // partially flushed temporary database is never promoted
+        assert!(!path.exists());
+
+        let exporter = SqliteExporter::new(Duration::from_secs(60), None, ":memory:").unwrap();
+        exporter.record_value("requests", 1.0, MetricKind::Counter);
+        exporter.shutdown_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_current_values() {
+        let exporter = SqliteExporter::new(Duration::from_secs(60), None, ":memory:").unwrap();
+        exporter.record_value("requests", 2.0, MetricKind::Counter);
+        exporter.record_value("requests", 3.0, MetricKind::Counter);
+        exporter.record_value("temperature", 21.5, MetricKind::Gauge);
+        exporter.record_value("latency", 4.0, MetricKind::Histogram);
+        let values = exporter.current_values();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["requests"], 5.0);
+        assert_eq!(values["temperature"], 21.5);
+    }
+
+    #[test]
+    fn test_record_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.db");
+        let exporter = SqliteExporter::new(Duration::from_secs(60), None, &path).unwrap();
+        exporter.record_value("requests", 1.0, MetricKind::Counter);
+        exporter.record_event("deploy_version", "1.4.2");
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let events = db
+            .events_in_range(Timestamp(0.0), Timestamp(f64::MAX))
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "deploy_version");
+        assert_eq!(events[0].value, "1.4.2");
+        assert!(db
+            .events_in_range(Timestamp(0.0), Timestamp(1.0))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_dropped_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dropped.db");
+        let exporter = SqliteExporter::builder()
+            .dropped_count_key("metrics_sqlite.dropped")
+            .build(&path)
+            .unwrap();
+        let before_epoch = std::time::SystemTime::UNIX_EPOCH - Duration::from_secs(1);
+        let key = Key::from_name("requests");
+        exporter.increment_counter_at(&key, 1, before_epoch);
+        exporter.increment_counter_at(&key, 1, before_epoch);
+        exporter.record_value("requests", 1.0, MetricKind::Counter);
+        assert_eq!(exporter.dropped_count(), 2);
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let dropped = db.metrics_for_key("metrics_sqlite.dropped", None).unwrap();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].value.0, 2.0);
+    }
+
+    #[test]
+    fn test_dropped_events_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dropped-events.db");
+        let mut exporter = SqliteExporter::builder().build(&path).unwrap();
+        // worker gone, so nothing can be sent anymore
+        exporter.stop(None).unwrap();
+        exporter.record_event("mode", "idle");
+        exporter.record_value("requests", 1.0, MetricKind::Counter);
+        assert_eq!(exporter.dropped_count(), 2);
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("buckets.db");
+        let exporter = SqliteExporter::builder()
+            .histogram_buckets([1.0, 5.0])
+            .build(&path)
+            .unwrap();
+        for value in [0.5, 1.0, 3.0, 10.0, 20.0] {
+            exporter.record_value("latency", value, MetricKind::Histogram);
+        }
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let buckets: Vec<_> = db
+            .histogram_buckets_for_key("latency", None)
+            .unwrap()
+            .into_iter()
+            .map(|bucket| (bucket.upper_bound, bucket.count))
+            .collect();
+        assert_eq!(buckets, vec![(1.0, 2), (5.0, 1), (f64::INFINITY, 2)]);
+        assert_eq!(db.metrics_for_key("latency", None).unwrap().len(), 5);
+        // buckets reference their key, so have to go before it
+        db.clear_all().unwrap();
+    }
+
+    #[test]
+    fn test_labels() {
+        use metrics::{Label, Recorder};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("labels.db");
+        let exporter = SqliteExporter::new(Duration::from_secs(60), None, &path).unwrap();
+        for (host, value) in [("a", 1), ("b", 2)] {
+            let labels = vec![Label::new("region", "eu"), Label::new("host", host)];
+            let key = Key::from_parts("requests", labels);
+            exporter.register_counter(&key).increment(value);
+        }
+        exporter
+            .register_counter(&Key::from_name("requests"))
+            .increment(3);
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.available_keys().unwrap(), vec!["requests".to_string()]);
+        let sets = db.label_sets_for_key("requests").unwrap();
+        assert_eq!(sets.len(), 3);
+        assert!(sets[0].is_empty());
+        assert_eq!(
+            sets[2],
+            vec![
+                ("host".to_string(), "b".to_string()),
+                ("region".to_string(), "eu".to_string())
+            ]
+        );
+        let labeled = db
+            .metrics_for_key_with_labels("requests", &[("region", "eu"), ("host", "b")], None)
+            .unwrap();
+        assert_eq!(labeled.len(), 1);
+        assert_eq!(labeled[0].value, Value(2.0));
+        assert_eq!(
+            db.metrics_for_key("requests", None).unwrap()[0].value,
+            Value(3.0)
+        );
+    }
+
+    #[test]
+    fn test_label_escaping() {
+        use metrics::{Label, Recorder};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("label-escaping.db");
+        let exporter = SqliteExporter::new(Duration::from_secs(60), None, &path).unwrap();
+        let joined = Key::from_parts("requests", vec![Label::new("a", "1,b=2")]);
+        let split = Key::from_parts("requests", vec![Label::new("a", "1"), Label::new("b", "2")]);
+        exporter.register_counter(&joined).increment(1);
+        exporter.register_counter(&split).increment(2);
+        exporter.describe_counter("requests".into(), None, "Requests served".into());
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let pair = |name: &str, value: &str| (name.to_string(), value.to_string());
+        assert_eq!(
+            db.label_sets_for_key("requests").unwrap(),
+            vec![
+                vec![],
+                vec![pair("a", "1"), pair("b", "2")],
+                vec![pair("a", "1,b=2")]
+            ]
+        );
+        let joined = db
+            .metrics_for_key_with_labels("requests", &[("a", "1,b=2")], None)
+            .unwrap();
+        assert_eq!(joined[0].value, Value(1.0));
+        assert!(db
+            .render_prometheus()
+            .unwrap()
+            .contains("requests{a=\"1,b=2\"} 1 "));
+        // only labeled series were recorded, so there's no single series to query
+        assert!(matches!(
+            db.metrics_for_key("requests", None),
+            Err(MetricsError::LabeledKey(_))
+        ));
+        assert_eq!(db.kind_for_key("requests").unwrap(), MetricKind::Counter);
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let build = |builder: crate::SqliteExporterBuilder| builder.build(":memory:").err();
+        assert!(matches!(
+            build(SqliteExporter::builder().flush_interval(Duration::ZERO)),
+            Some(MetricsError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            build(SqliteExporter::builder().channel_capacity(0)),
+            Some(MetricsError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            build(SqliteExporter::builder().histogram_buckets([5.0, 1.0])),
+            Some(MetricsError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            build(SqliteExporter::builder().housekeeping_period(Duration::from_secs(60))),
+            Some(MetricsError::InvalidRetentionPolicy(_))
+        ));
+        assert!(build(
+            SqliteExporter::builder()
+                .housekeeping_period(Duration::from_secs(60))
+                .record_limit(1000)
+                .flush_queue_limit(10)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint.db");
+        let exporter = SqliteExporter::builder()
+            .priority_keys(["a"])
+            .busy_timeout(Duration::from_secs(1))
+            .build(&path)
+            .unwrap();
+        exporter.record_value("a", 1.0, MetricKind::Gauge);
+        exporter.checkpoint().unwrap();
+        let wal = path.with_extension("db-wal");
+        assert!(wal.exists());
+        assert_eq!(std::fs::metadata(&wal).map(|m| m.len()).unwrap_or(0), 0);
+        drop(exporter);
+        let exporter = SqliteExporter::builder()
+            .wal_mode(false)
+            .build(&path)
+            .unwrap();
+        exporter.record_value("a", 2.0, MetricKind::Gauge);
+        exporter.checkpoint().unwrap();
+        assert!(!wal.exists());
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vacuum.db");
+        let exporter = SqliteExporter::builder()
+            .flush_interval(Duration::from_secs(60))
+            .build(&path)
+            .unwrap();
+        exporter.record_value("a", 1.0, MetricKind::Gauge);
+        exporter.vacuum().unwrap();
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_used_bytes() {
+        let mut db = setup_db(":memory:").unwrap();
+        assert!(used_bytes(&mut db).unwrap() > 0);
+    }
+
     #[test]
     fn test_threading() {
         use std::thread;
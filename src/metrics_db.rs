@@ -1,22 +1,112 @@
 //! Metrics DB, to use/query/etc metrics SQLite databases
-use super::{models::Metric, setup_db, Result};
-use crate::models::MetricKey;
-use crate::MetricsError;
+use super::{
+    models::Metric, set_busy_timeout, setup_db, setup_db_with_migrations, Result,
+    DEFAULT_BUSY_TIMEOUT,
+};
+use crate::models::{
+    ArchivedMetric, HistogramBucket, KeyIdCache, MetricKey, MetricKind, NewMetric, RecordedEvent,
+    Timestamp, Value,
+};
+use crate::{KeyNormalizer, MetricsError};
+use diesel::connection::{AnsiTransactionManager, TransactionManager};
 use diesel::prelude::*;
+use diesel::sql_query;
+use diesel_migrations::EmbeddedMigrations;
+use metrics::Unit;
 #[cfg(feature = "import_csv")]
 use serde::Deserialize;
-use std::path::Path;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /// Threshold to separate samples into sessions by
 const SESSION_TIME_GAP_THRESHOLD: Duration = Duration::from_secs(30);
 
+/// Number of rows deleted per statement by `compact_key()`
+const COMPACT_DELETE_CHUNK: usize = 500;
+
+/// Bytes per sample assumed by `growth_rate()` when there are no samples to measure
+const ESTIMATED_SAMPLE_BYTES: f64 = 40.0;
+/// Number of timestamps loaded at a time by `latest_session()`
+const LATEST_SESSION_PAGE_SIZE: i64 = 1000;
+
 /// Calculated metric type from deriv_metrics_for_key()
 #[derive(Debug)]
 pub struct DerivMetric {
-    pub timestamp: f64,
+    /// Timestamp of later sample the rate is calculated at
+    pub timestamp: Timestamp,
+    /// Source key name suffixed with `.deriv`
     pub key: String,
-    pub value: f64,
+    /// Rate of change per second
+    pub value: Value,
+}
+/// Aggregate function applied to samples
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BucketAgg {
+    /// Average value
+    Mean,
+    /// Lowest value
+    Min,
+    /// Highest value
+    Max,
+    /// Sum of values
+    Sum,
+    /// Number of samples
+    Count,
+}
+/// Thresholds used to split samples into sessions
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SessionThresholds {
+    /// Gaps longer than this split sessions, considered a normal pause
+    pub pause: Duration,
+    /// Gaps longer than this are considered downtime (e.g. app off overnight), None to treat
+    /// every gap as a pause
+    pub downtime: Option<Duration>,
+}
+impl Default for SessionThresholds {
+    fn default() -> Self {
+        SessionThresholds {
+            pause: SESSION_TIME_GAP_THRESHOLD,
+            downtime: None,
+        }
+    }
+}
+/// Kind of gap separating a session from the one before it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SessionGap {
+    /// Gap exceeded pause threshold only
+    Pause,
+    /// Gap exceeded downtime threshold
+    Downtime,
+}
+/// Database health summary, see `MetricsDb::health()`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HealthReport {
+    /// Version of newest migration applied
+    pub schema_version: String,
+    /// True if all of this crate's migrations are applied
+    pub schema_current: bool,
+    /// Total number of samples stored
+    pub sample_count: i64,
+    /// Timestamp of newest sample, None if database has no samples
+    pub newest_timestamp: Option<f64>,
+    /// Time since newest sample, None if database has no samples
+    pub newest_age: Option<Duration>,
+}
+/// Point of a key's series compared across two databases, see `MetricsDb::diff()`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DiffPoint {
+    /// Seconds since the key's first sample in this database
+    pub offset: f64,
+    /// Value in this database
+    pub value: Value,
+    /// Value in other database
+    pub other_value: Value,
+    /// `other_value - value`
+    pub difference: f64,
 }
 /// Describes a session, which is a sub-set of metrics data based on time gaps
 #[derive(Debug, Copy, Clone)]
@@ -27,6 +117,8 @@ pub struct Session {
     pub end_time: f64,
     /// Duration of session
     pub duration: Duration,
+    /// Gap separating this session from previous one, None for the first session
+    pub preceded_by: Option<SessionGap>,
 }
 impl Session {
     /// Creates a new session with given start & end, calculating duration from them
@@ -35,54 +127,455 @@ impl Session {
             start_time,
             end_time,
             duration: Duration::from_secs_f64(end_time - start_time),
+            preceded_by: None,
+        }
+    }
+}
+/// Cheaply clonable handle to a metrics database, opening a short-lived read-only connection for
+/// each query so it can be shared across threads/tasks without locking a `MetricsDb`
+#[derive(Debug, Clone)]
+pub struct MetricsDbHandle {
+    path: Arc<PathBuf>,
+}
+impl MetricsDbHandle {
+    /// Creates a handle for SQLite database at given path, doesn't open it until queried
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        MetricsDbHandle {
+            path: Arc::new(path.as_ref().to_path_buf()),
         }
     }
+
+    /// Path of database this handle queries
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens a read-only connection to the database & runs given query with it
+    ///
+    /// Database must already exist & be migrated, e.g. by a running `SqliteExporter`
+    pub fn query<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut MetricsDb) -> Result<T>,
+    {
+        let mut db = MetricsDb::open_read_only(&self.path)?;
+        f(&mut db)
+    }
 }
 /// Metrics database, useful for querying stored metrics
 pub struct MetricsDb {
     db: SqliteConnection,
-    sessions: Vec<Session>,
+    /// Calculated on first use, `None` until then or after samples change
+    sessions: Option<Vec<Session>>,
+    session_thresholds: SessionThresholds,
+    session_window: Option<f64>,
+    key_ids: KeyIdCache,
+    key_normalizer: Option<KeyNormalizer>,
 }
 
 impl MetricsDb {
     /// Creates a new metrics DB with given path of a SQLite database
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let db = setup_db(path)?;
+        Self::from_connection(db, None)
+    }
+
+    /// Creates a new metrics DB with given path of a SQLite database, only considering samples
+    /// after `since` timestamp when calculating sessions
+    ///
+    /// Avoids scanning every timestamp of a large database when only recent sessions matter
+    pub fn new_with_window<P: AsRef<Path>>(path: P, since: Timestamp) -> Result<Self> {
+        let db = setup_db(path)?;
+        Self::from_connection(db, Some(since.0))
+    }
+
+    /// Creates a new metrics DB with given path of a SQLite database, applying given migrations
+    /// after the built-in ones, for extending the schema
+    ///
+    /// Migration versions must not clash with the built-in migrations
+    pub fn new_with_migrations<P: AsRef<Path>>(
+        path: P,
+        migrations: &EmbeddedMigrations,
+    ) -> Result<Self> {
+        let db = setup_db_with_migrations(path, Some(migrations), true)?;
+        Self::from_connection(db, None)
+    }
+
+    /// Creates a new metrics DB, calling `register` on the connection first to register
+    /// application defined SQL functions (e.g. via diesel's `define_sql_function!`), usable in
+    /// queries run through `with_connection()`
+    ///
+    /// Functions are per connection, they need registering again whenever a `MetricsDb` is opened
+    pub fn new_with_functions<P, F>(path: P, register: F) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut SqliteConnection) -> QueryResult<()>,
+    {
         let mut db = setup_db(path)?;
-        let sessions = Self::process_sessions(&mut db)?;
-        Ok(MetricsDb { db, sessions })
+        register(&mut db)?;
+        Self::from_connection(db, None)
+    }
+
+    /// Runs given closure with the underlying connection, for custom queries beyond what
+    /// `MetricsDb` provides
+    pub fn with_connection<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut SqliteConnection) -> QueryResult<T>,
+    {
+        Ok(f(&mut self.db)?)
+    }
+
+    fn from_connection(db: SqliteConnection, session_window: Option<f64>) -> Result<Self> {
+        Ok(MetricsDb {
+            db,
+            sessions: None,
+            session_thresholds: SessionThresholds::default(),
+            session_window,
+            key_ids: KeyIdCache::default(),
+            key_normalizer: None,
+        })
+    }
+
+    /// Opens existing SQLite database without running migrations, refusing any writes
+    fn open_read_only(path: &Path) -> Result<Self> {
+        let db = Self::establish_read_only(path)?;
+        Self::from_connection(db, None)
+    }
+
+    pub(crate) fn establish_read_only(path: &Path) -> Result<SqliteConnection> {
+        if !path.is_file() {
+            return Err(MetricsError::InvalidDatabasePath);
+        }
+        let url = path.to_str().ok_or(MetricsError::InvalidDatabasePath)?;
+        let mut db = SqliteConnection::establish(url)?;
+        set_busy_timeout(&mut db, DEFAULT_BUSY_TIMEOUT)?;
+        sql_query("PRAGMA query_only = ON").execute(&mut db)?;
+        Ok(db)
+    }
+
+    /// Opens existing database read-only for querying while a `SqliteExporter` writes to it, every
+    /// query seeing the database as it was when opened until `refresh_snapshot()` is called
+    ///
+    /// Relies on the database being in WAL mode (the default, see
+    /// `SqliteExporterBuilder::wal_mode()`) so the snapshot never blocks the writer. Otherwise the
+    /// snapshot's read lock stops the writer committing until it's refreshed or dropped, & a
+    /// warning is logged.
+    pub fn open_snapshot<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut db = Self::establish_read_only(path.as_ref())?;
+        let mode = sql_query("PRAGMA journal_mode").get_result::<JournalModeRow>(&mut db)?;
+        if !mode.journal_mode.eq_ignore_ascii_case("wal") {
+            warn!(
+                "Snapshot of database in {} journal mode will block writers, WAL recommended",
+                mode.journal_mode
+            );
+        }
+        // reads within a transaction share the snapshot taken by its first read, begun through
+        // diesel so methods running their own transaction nest a savepoint within it
+        AnsiTransactionManager::begin_transaction(&mut db)?;
+        Self::from_connection(db, None)
+    }
+
+    /// Moves a snapshot opened with `open_snapshot()` up to the database's current contents,
+    /// recalculating sessions on next use
+    pub fn refresh_snapshot(&mut self) -> Result<()> {
+        AnsiTransactionManager::commit_transaction(&mut self.db)?;
+        AnsiTransactionManager::begin_transaction(&mut self.db)?;
+        self.sessions = None;
+        Ok(())
+    }
+
+    /// Returns sessions in database, based on session thresholds (`SESSION_TIME_GAP_THRESHOLD` by
+    /// default)
+    ///
+    /// Sessions are calculated on first call by scanning every timestamp (or those in the window
+    /// given to `new_with_window()`) & cached until samples are deleted through this `MetricsDb`
+    pub fn sessions(&mut self) -> Result<Vec<Session>> {
+        Ok(self.cached_sessions()?.clone())
     }
 
-    /// Returns sessions in database, based on `SESSION_TIME_GAP_THRESHOLD`
-    pub fn sessions(&self) -> Vec<Session> {
-        self.sessions.clone()
+    /// Returns up to `n` most recent sessions, most recent first
+    pub fn recent_sessions(&mut self, n: usize) -> Result<Vec<Session>> {
+        Ok(self
+            .cached_sessions()?
+            .iter()
+            .rev()
+            .take(n)
+            .copied()
+            .collect())
     }
 
-    fn process_sessions(db: &mut SqliteConnection) -> Result<Vec<Session>> {
+    /// Sets thresholds used to split sessions, recalculating sessions on next use
+    pub fn set_session_thresholds(&mut self, thresholds: SessionThresholds) -> Result<()> {
+        self.session_thresholds = thresholds;
+        self.sessions = None;
+        Ok(())
+    }
+
+    fn cached_sessions(&mut self) -> Result<&Vec<Session>> {
+        if self.sessions.is_none() {
+            let sessions = match Self::process_sessions(
+                &mut self.db,
+                &self.session_thresholds,
+                self.session_window,
+            ) {
+                Err(MetricsError::EmptyDatabase) => Vec::new(),
+                sessions => sessions?,
+            };
+            self.sessions = Some(sessions);
+        }
+        Ok(self.sessions.get_or_insert_with(Vec::new))
+    }
+
+    /// Returns most recent session without calculating every session, scanning timestamps
+    /// backwards from the newest sample until a gap exceeds the pause threshold
+    ///
+    /// Unlike `sessions()` a lone sample after a gap still counts as a (zero length) session,
+    /// since it's the start of the current run
+    pub fn latest_session(&mut self) -> Result<Option<Session>> {
         use crate::schema::metrics::dsl::*;
-        let timestamps = metrics
-            .select(timestamp)
-            .order(timestamp.asc())
-            .load::<f64>(db)?;
+        let pause = self.session_thresholds.pause.as_secs_f64();
+        let mut end_time = None;
+        let mut start_time = None;
+        loop {
+            let query = metrics
+                .select(timestamp)
+                .order(timestamp.desc())
+                .limit(LATEST_SESSION_PAGE_SIZE);
+            let page = match start_time {
+                Some(start) => query
+                    .filter(timestamp.lt(start))
+                    .load::<f64>(&mut self.db)?,
+                None => query.load::<f64>(&mut self.db)?,
+            };
+            if page.is_empty() {
+                break;
+            }
+            for ts in &page {
+                if let Some(start) = start_time {
+                    let gap = start - ts;
+                    if gap > pause {
+                        let preceded_by = match self.session_thresholds.downtime {
+                            Some(downtime) if gap > downtime.as_secs_f64() => SessionGap::Downtime,
+                            _ => SessionGap::Pause,
+                        };
+                        return Ok(end_time.map(|end| Session {
+                            preceded_by: Some(preceded_by),
+                            ..Session::new(start, end)
+                        }));
+                    }
+                }
+                end_time.get_or_insert(*ts);
+                start_time = Some(*ts);
+            }
+        }
+        Ok(start_time
+            .zip(end_time)
+            .map(|(start, end)| Session::new(start, end)))
+    }
+
+    fn process_sessions(
+        db: &mut SqliteConnection,
+        thresholds: &SessionThresholds,
+        since: Option<f64>,
+    ) -> Result<Vec<Session>> {
+        use crate::schema::metrics::dsl::*;
+        let query = metrics.select(timestamp).order(timestamp.asc());
+        let timestamps = match since {
+            Some(since) => query.filter(timestamp.gt(since)).load::<f64>(db)?,
+            None => query.load::<f64>(db)?,
+        };
         if timestamps.is_empty() {
-            return Err(MetricsError::EmptyDatabase);
+            return match since {
+                Some(_) => Ok(Vec::new()),
+                None => Err(MetricsError::EmptyDatabase),
+            };
         }
         let mut sessions: Vec<Session> = Vec::new();
         let mut current_start = timestamps[0];
+        let mut current_gap = None;
         for pair in timestamps.windows(2) {
-            if pair[1] - pair[0] > SESSION_TIME_GAP_THRESHOLD.as_secs_f64() {
-                sessions.push(Session::new(current_start, pair[0]));
+            let gap = pair[1] - pair[0];
+            if gap > thresholds.pause.as_secs_f64() {
+                sessions.push(Session {
+                    preceded_by: current_gap,
+                    ..Session::new(current_start, pair[0])
+                });
                 current_start = pair[1];
+                current_gap = match thresholds.downtime {
+                    Some(downtime) if gap > downtime.as_secs_f64() => Some(SessionGap::Downtime),
+                    _ => Some(SessionGap::Pause),
+                };
             }
         }
         if let Some(last) = timestamps.last() {
             if current_start < *last {
-                sessions.push(Session::new(current_start, *last));
+                sessions.push(Session {
+                    preceded_by: current_gap,
+                    ..Session::new(current_start, *last)
+                });
             }
         }
 
         Ok(sessions)
     }
 
+    /// Deletes samples older than the last `n` sessions (split by default thresholds), returning
+    /// number of samples removed
+    pub(crate) fn keep_last_sessions(db: &mut SqliteConnection, n: usize) -> Result<usize> {
+        let sessions = match Self::process_sessions(db, &SessionThresholds::default(), None) {
+            Err(MetricsError::EmptyDatabase) => return Ok(0),
+            sessions => sessions?,
+        };
+        if sessions.len() <= n {
+            return Ok(0);
+        }
+        let cutoff = sessions[sessions.len() - n].start_time;
+        let removed = db.transaction::<_, diesel::result::Error, _>(|db| {
+            use crate::schema::{events, histogram_buckets, histogram_summaries, metrics};
+            diesel::delete(
+                histogram_summaries::table.filter(histogram_summaries::timestamp.lt(cutoff)),
+            )
+            .execute(db)?;
+            diesel::delete(
+                histogram_buckets::table.filter(histogram_buckets::timestamp.lt(cutoff)),
+            )
+            .execute(db)?;
+            diesel::delete(events::table.filter(events::timestamp.lt(cutoff))).execute(db)?;
+            diesel::delete(metrics::table.filter(metrics::timestamp.lt(cutoff))).execute(db)
+        })?;
+        Ok(removed)
+    }
+
+    /// Rolls samples older than `cutoff` up into `resolution` second buckets in the archive table,
+    /// removing them from the metrics table & returning number of samples archived
+    ///
+    /// Cutoff is rounded down to a bucket boundary so repeated runs never split a bucket.
+    pub(crate) fn archive_before(
+        db: &mut SqliteConnection,
+        cutoff: f64,
+        resolution: f64,
+    ) -> Result<usize> {
+        use diesel::sql_types::Double;
+        let cutoff = (cutoff / resolution).floor() * resolution;
+        let archived = db.transaction::<_, diesel::result::Error, _>(|db| {
+            sql_query(
+                "INSERT INTO metrics_archive \
+                 (metric_key_id, timestamp, resolution, sample_count, min_value, max_value, mean_value) \
+                 SELECT metric_key_id, CAST(timestamp / ?1 AS INTEGER) * ?1 AS bucket, ?1, \
+                 COUNT(*), MIN(value), MAX(value), AVG(value) FROM metrics \
+                 WHERE timestamp < ?2 GROUP BY metric_key_id, bucket",
+            )
+            .bind::<Double, _>(resolution)
+            .bind::<Double, _>(cutoff)
+            .execute(db)?;
+            use crate::schema::metrics;
+            diesel::delete(metrics::table.filter(metrics::timestamp.lt(cutoff))).execute(db)
+        })?;
+        Ok(archived)
+    }
+
+    /// Returns archived rollups of given key in timestamp order
+    pub fn archived_metrics_for_key(&mut self, key_name: &str) -> Result<Vec<ArchivedMetric>> {
+        use crate::schema::metrics_archive::dsl::*;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let r = metrics_archive
+            .filter(metric_key_id.eq(metric_key.id))
+            .order(timestamp.asc())
+            .select(ArchivedMetric::as_select())
+            .load(&mut self.db)?;
+        Ok(r)
+    }
+
+    /// Returns given key's values with timestamps in `[start, end]`, using rollup means from the
+    /// archive for the part of the range that's been archived & samples for the rest
+    pub fn values_for_key_in_range(
+        &mut self,
+        key_name: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<(Timestamp, Value)>> {
+        use diesel::sql_types::{BigInt, Double};
+        let key_id = self.metric_key_for_key(key_name)?.id;
+        let rows = sql_query(
+            "SELECT timestamp, mean_value AS value FROM metrics_archive \
+             WHERE metric_key_id = ?1 AND timestamp BETWEEN ?2 AND ?3 \
+             UNION ALL SELECT timestamp, value FROM metrics \
+             WHERE metric_key_id = ?1 AND timestamp BETWEEN ?2 AND ?3 \
+             ORDER BY timestamp",
+        )
+        .bind::<BigInt, _>(key_id)
+        .bind::<Double, _>(start.0)
+        .bind::<Double, _>(end.0)
+        .load::<SampleRow>(&mut self.db)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (Timestamp(row.timestamp), Value(row.value)))
+            .collect())
+    }
+
+    /// Returns schema version, sample count & freshness of newest sample in one call, e.g. to back
+    /// a health check endpoint
+    pub fn health(&mut self) -> Result<HealthReport> {
+        use crate::schema::metrics::dsl::*;
+        use diesel::dsl::{count_star, max};
+        use diesel_migrations::MigrationHarness;
+        let schema_version = self
+            .db
+            .applied_migrations()
+            .map_err(MetricsError::MigrationError)?
+            .into_iter()
+            .map(|version| version.to_string())
+            .max()
+            .unwrap_or_default();
+        let schema_current = !self
+            .db
+            .has_pending_migration(crate::MIGRATIONS)
+            .map_err(MetricsError::MigrationError)?;
+        let (sample_count, newest_timestamp) = metrics
+            .select((count_star(), max(timestamp)))
+            .first::<(i64, Option<f64>)>(&mut self.db)?;
+        let newest_age = newest_timestamp.map(|newest| {
+            SystemTime::UNIX_EPOCH
+                .elapsed()
+                .map(|now| now.saturating_sub(Duration::from_secs_f64(newest.max(0.0))))
+                .unwrap_or_default()
+        });
+        Ok(HealthReport {
+            schema_version,
+            schema_current,
+            sample_count,
+            newest_timestamp,
+            newest_age,
+        })
+    }
+
+    /// Returns estimated database growth in bytes per second, from the rate samples were recorded
+    /// over the last `window` times the database's average bytes per sample (including indexes)
+    ///
+    /// Ignores housekeeping, so it's the rate before retention limits start deleting data
+    pub fn growth_rate(&mut self, window: Duration) -> Result<f64> {
+        use crate::schema::metrics::dsl::*;
+        use diesel::dsl::count_star;
+        if window.is_zero() {
+            return Err(MetricsError::InvalidArgument(
+                "window must be greater than zero",
+            ));
+        }
+        let cutoff = SystemTime::UNIX_EPOCH
+            .elapsed()
+            .map(|now| now.saturating_sub(window).as_secs_f64())
+            .unwrap_or(0.0);
+        let recent = metrics
+            .filter(timestamp.ge(cutoff))
+            .select(count_star())
+            .first::<i64>(&mut self.db)?;
+        let total = metrics.select(count_star()).first::<i64>(&mut self.db)?;
+        let bytes_per_sample = match total {
+            0 => ESTIMATED_SAMPLE_BYTES,
+            total => crate::used_bytes(&mut self.db)? as f64 / total as f64,
+        };
+        Ok(recent as f64 / window.as_secs_f64() * bytes_per_sample)
+    }
+
     /// Returns list of metrics keys stored in the database
     pub fn available_keys(&mut self) -> Result<Vec<String>> {
         use crate::schema::metric_keys::dsl::*;
@@ -93,140 +586,3107 @@ impl MetricsDb {
         Ok(r)
     }
 
+    /// Returns number of samples stored for every key in a single query, keys without samples
+    /// counting 0 & labeled series counting towards their key
+    pub fn sample_counts(&mut self) -> Result<HashMap<String, i64>> {
+        use crate::schema::{metric_keys, metrics};
+        use diesel::dsl::count;
+        let r = metric_keys::table
+            .left_join(metrics::table)
+            .group_by(metric_keys::key)
+            .select((metric_keys::key, count(metrics::id.nullable())))
+            .load::<(String, i64)>(&mut self.db)?;
+        Ok(r.into_iter().collect())
+    }
+
+    /// Returns label sets recorded for given key as sorted `(name, value)` pairs, an empty set
+    /// being the unlabeled series
+    ///
+    /// Each label set is a separate series, query them with `metrics_for_key_with_labels()`
+    pub fn label_sets_for_key(&mut self, key_name: &str) -> Result<Vec<Vec<(String, String)>>> {
+        use crate::schema::metric_keys::dsl::*;
+        let normalized = self.normalize_key(key_name).into_owned();
+        let sets = metric_keys
+            .filter(key.eq(normalized))
+            .order(labels.asc())
+            .select(labels)
+            .load::<String>(&mut self.db)?;
+        Ok(sets
+            .iter()
+            .map(|set| crate::models::parse_labels(set))
+            .collect())
+    }
+
     /// Returns all metrics for given key in ascending timestamp order
+    ///
+    /// Only covers the unlabeled series, see `metrics_for_key_with_labels()` for labeled ones.
+    /// Like other single key queries it fails with `MetricsError::LabeledKey` if the key was only
+    /// recorded with labels.
     pub fn metrics_for_key(
         &mut self,
         key_name: &str,
         session: Option<&Session>,
+    ) -> Result<Vec<Metric>> {
+        self.metrics_for_key_with_labels(key_name, &[], session)
+    }
+
+    /// Returns all metrics of the series of given key with exactly the given labels (in any
+    /// order) in ascending timestamp order, e.g. `&[("host", "a")]` for `counter!("requests",
+    /// "host" => "a")`
+    pub fn metrics_for_key_with_labels(
+        &mut self,
+        key_name: &str,
+        key_labels: &[(&str, &str)],
+        session: Option<&Session>,
     ) -> Result<Vec<Metric>> {
         use crate::schema::metrics::dsl::*;
-        let metric_key = self.metric_key_for_key(key_name)?;
+        let key_labels = crate::models::labels_string(key_labels.iter().copied());
+        let metric_key = self.metric_key_for_labels(key_name, &key_labels)?;
         let query = metrics
-            .order(timestamp.asc())
+            .order((timestamp.asc(), id.asc()))
             .filter(metric_key_id.eq(metric_key.id));
         let r = match session {
             Some(session) => query
                 .filter(timestamp.ge(session.start_time))
                 .filter(timestamp.le(session.end_time))
-                .load::<Metric>(&mut self.db)?,
-            None => query.load::<Metric>(&mut self.db)?,
+                .select(Metric::as_select())
+                .load(&mut self.db)?,
+            None => query.select(Metric::as_select()).load(&mut self.db)?,
         };
         Ok(r)
     }
 
-    fn metric_key_for_key(&mut self, key_name: &str) -> Result<MetricKey> {
-        use crate::schema::metric_keys::dsl::*;
-        let query = metric_keys.filter(key.eq(key_name));
-        let keys = query.load::<MetricKey>(&mut self.db)?;
-        keys.into_iter()
-            .next()
-            .ok_or_else(|| MetricsError::KeyNotFound(key_name.to_string()))
+    /// Returns metrics for given key recorded between `start` & `end` inclusive in ascending
+    /// timestamp order, e.g. for "last hour" views without building a `Session`
+    ///
+    /// Only covers raw samples, see `values_for_key_in_range()` to include archived rollups
+    pub fn metrics_for_key_in_range(
+        &mut self,
+        key_name: &str,
+        start: SystemTime,
+        end: SystemTime,
+    ) -> Result<Vec<Metric>> {
+        use crate::schema::metrics::dsl::*;
+        let secs = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+        };
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let r = metrics
+            .filter(metric_key_id.eq(metric_key.id))
+            .filter(timestamp.ge(secs(start)))
+            .filter(timestamp.le(secs(end)))
+            .order((timestamp.asc(), id.asc()))
+            .select(Metric::as_select())
+            .load(&mut self.db)?;
+        Ok(r)
     }
 
-    /// Returns rate of change, the derivative, of the given metrics key's values
+    /// Returns all metrics for given key like `metrics_for_key()`, with values converted from the
+    /// key's stored unit to `target`, e.g. bytes to mebibytes
     ///
-    /// f(t) = (x(t + 1) - x(t)) / ((t+1) - (t)
-    pub fn deriv_metrics_for_key(
+    /// Errors if the key has no unit stored or it measures something other than `target`
+    pub fn metrics_for_key_converted(
         &mut self,
         key_name: &str,
+        target: Unit,
         session: Option<&Session>,
-    ) -> Result<Vec<DerivMetric>> {
-        let m = self.metrics_for_key(key_name, session)?;
-        let new_values: Vec<_> = m
-            .windows(2)
-            .map(|v| {
-                let new_value =
-                    (v[1].value - v[0].value) / (v[1].timestamp - v[0].timestamp);
-                DerivMetric {
-                    timestamp: v[1].timestamp,
-                    key: format!("{}.deriv", key_name),
-                    value: new_value,
-                }
+    ) -> Result<Vec<Metric>> {
+        let stored = self.metric_key_for_key(key_name)?.unit.into_owned();
+        let incompatible = || MetricsError::IncompatibleUnits {
+            from: stored.clone(),
+            to: target.as_str().to_string(),
+        };
+        let (from_dimension, from_scale) = Unit::from_string(&stored)
+            .map(unit_scale)
+            .ok_or_else(incompatible)?;
+        let (to_dimension, to_scale) = unit_scale(target);
+        if from_dimension != to_dimension {
+            return Err(incompatible());
+        }
+        let factor = from_scale / to_scale;
+        let mut metrics = self.metrics_for_key(key_name, session)?;
+        for metric in &mut metrics {
+            metric.value.0 *= factor;
+        }
+        Ok(metrics)
+    }
+
+    /// Returns given key's samples as `(timestamp, value, call_count)` in ascending timestamp
+    /// order, call count being how many times the key was recorded since its previous stored
+    /// sample
+    ///
+    /// Call count is None for samples stored without `SqliteExporterBuilder::record_call_counts()`
+    pub fn metrics_with_call_counts_for_key(
+        &mut self,
+        key_name: &str,
+        session: Option<&Session>,
+    ) -> Result<Vec<(Timestamp, Value, Option<u64>)>> {
+        use crate::schema::metrics::dsl::*;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let mut query = metrics
+            .filter(metric_key_id.eq(metric_key.id))
+            .order((timestamp.asc(), id.asc()))
+            .select((timestamp, value, call_count))
+            .into_boxed();
+        if let Some(session) = session {
+            query = query.filter(timestamp.between(session.start_time, session.end_time));
+        }
+        let rows = query.load::<(Timestamp, Value, Option<i64>)>(&mut self.db)?;
+        Ok(rows
+            .into_iter()
+            .map(|(t, v, count)| (t, v, count.map(|count| count as u64)))
+            .collect())
+    }
+
+    /// Returns the empirical CDF of given key's values as `(value, cumulative_fraction)` at
+    /// `points` evenly spaced fractions up to 1.0, e.g. to chart a latency distribution
+    ///
+    /// Values are taken by nearest rank
+    pub fn cdf_for_key(
+        &mut self,
+        key_name: &str,
+        points: usize,
+        session: Option<&Session>,
+    ) -> Result<Vec<(Value, f64)>> {
+        use crate::schema::metrics::dsl::*;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let mut query = metrics
+            .filter(metric_key_id.eq(metric_key.id))
+            .select(value)
+            .into_boxed();
+        if let Some(session) = session {
+            query = query.filter(timestamp.between(session.start_time, session.end_time));
+        }
+        let mut values = query.load::<f64>(&mut self.db)?;
+        if values.is_empty() {
+            return Ok(Vec::new());
+        }
+        values.sort_by(f64::total_cmp);
+        let n = values.len();
+        Ok((1..=points)
+            .map(|i| {
+                let fraction = i as f64 / points as f64;
+                let rank = ((fraction * n as f64).ceil() as usize).clamp(1, n);
+                (Value(values[rank - 1]), fraction)
             })
-            .collect();
-        Ok(new_values)
+            .collect())
     }
 
-    /// Exports DB contents to CSV file
-    #[cfg(feature = "export_csv")]
-    pub fn export_to_csv<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        use crate::schema::metric_keys::dsl::key;
+    /// Returns the `q` quantile (0.0 to 1.0) of given key's values by nearest rank, e.g. `0.95`
+    /// for p95 latency
+    ///
+    /// Only the selected sample is loaded, errors with `MetricsError::NoMetricsForKey` if there
+    /// are none
+    pub fn quantile_for_key(
+        &mut self,
+        key_name: &str,
+        session: Option<&Session>,
+        q: f64,
+    ) -> Result<f64> {
         use crate::schema::metrics::dsl::*;
-        use std::fs::File;
-        let out_file = File::create(path)?;
-        let mut csv_writer = csv::Writer::from_writer(out_file);
-        // join the 2 tables so we get a flat CSV with the actual key names
-        let query = crate::schema::metrics::table.inner_join(crate::schema::metric_keys::table);
-        let query = query
-            .order(timestamp.asc())
-            .select((id, timestamp, key, value));
-        for row in query.load::<JoinedMetric>(&mut self.db)? {
-            csv_writer.serialize(row)?;
+        if !(0.0..=1.0).contains(&q) {
+            return Err(MetricsError::InvalidArgument(
+                "quantile must be between 0.0 and 1.0",
+            ));
         }
-        csv_writer.flush()?;
-        Ok(())
+        let key_id = self.metric_key_for_key(key_name)?.id;
+        let filtered = || {
+            let mut query = metrics.filter(metric_key_id.eq(key_id)).into_boxed();
+            if let Some(session) = session {
+                query = query.filter(timestamp.between(session.start_time, session.end_time));
+            }
+            query
+        };
+        let n = filtered().count().get_result::<i64>(&mut self.db)?;
+        if n == 0 {
+            return Err(MetricsError::NoMetricsForKey(key_name.to_string()));
+        }
+        let rank = ((q * n as f64).ceil() as i64).clamp(1, n);
+        let r = filtered()
+            .select(value)
+            .order(value.asc())
+            .offset(rank - 1)
+            .first::<f64>(&mut self.db)?;
+        Ok(r)
     }
-    /// Imports CSV file into a MetricsDb file
-    #[cfg(feature = "import_csv")]
-    pub fn import_from_csv<S: AsRef<Path>, D: AsRef<Path>>(path: S, destination: D) -> Result<()> {
-        use crate::InnerState;
-        use csv::ReaderBuilder;
-        let db = setup_db(destination)?;
-        let mut reader = ReaderBuilder::new().from_path(path)?;
-        let mut inner = InnerState::new(Duration::from_secs(5), db);
-        let header = reader.headers()?.to_owned();
-        let mut flush_counter = 0u64;
-        for record in reader.records() {
-            match record {
-                Ok(record) => match record.deserialize::<MetricCsvRow>(Some(&header)) {
-                    Ok(r) => {
-                        if let Err(e) =
-                            inner.queue_metric(Duration::from_secs_f64(r.timestamp), r.key, r.value)
-                        {
-                            error!(
-                                "Skipping record due to error recording metric into DB: {:?}",
-                                e
-                            );
-                        }
-                        flush_counter += 1;
-                    }
-                    Err(e) => {
-                        error!("Skipping record due to error parsing CSV row: {:?}", e);
-                    }
-                },
-                Err(e) => {
-                    error!("Skipping record due to error reading CSV record: {:?}", e);
-                }
+
+    /// Returns a page of given key's samples in timestamp order, skipping `offset` samples
+    ///
+    /// Skipping gets slower the further in the page is, see `metrics_for_key_after()` for large
+    /// series
+    pub fn metrics_for_key_paged(
+        &mut self,
+        key_name: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Metric>> {
+        use crate::schema::metrics::dsl::*;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let r = metrics
+            .filter(metric_key_id.eq(metric_key.id))
+            .order((timestamp.asc(), id.asc()))
+            .offset(offset as i64)
+            .limit(limit as i64)
+            .select(Metric::as_select())
+            .load(&mut self.db)?;
+        Ok(r)
+    }
+
+    /// Returns a page of given key's samples in timestamp order following `after`, the last sample
+    /// of the previous page (None for the first page)
+    ///
+    /// Uses the sample's timestamp & ID as a cursor, so pages stay fast & stable deep into a series
+    pub fn metrics_for_key_after(
+        &mut self,
+        key_name: &str,
+        after: Option<&Metric>,
+        limit: usize,
+    ) -> Result<Vec<Metric>> {
+        use crate::schema::metrics::dsl::*;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let query = metrics
+            .filter(metric_key_id.eq(metric_key.id))
+            .order((timestamp.asc(), id.asc()))
+            .limit(limit as i64);
+        let r = match after {
+            Some(after) => query
+                .filter(
+                    timestamp
+                        .gt(after.timestamp)
+                        .or(timestamp.eq(after.timestamp).and(id.gt(after.id))),
+                )
+                .select(Metric::as_select())
+                .load(&mut self.db)?,
+            None => query.select(Metric::as_select()).load(&mut self.db)?,
+        };
+        Ok(r)
+    }
+
+    /// Returns given key's value at `at`, linearly interpolated between the samples either side of
+    /// it, or the nearest sample's value for counters. None if `at` is outside the key's samples.
+    pub fn value_at(&mut self, key_name: &str, at: Timestamp) -> Result<Option<Value>> {
+        use crate::schema::metrics::dsl::*;
+        let at = at.0;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let (key_id, kind) = (metric_key.id, metric_key.kind);
+        let before = metrics
+            .filter(metric_key_id.eq(key_id))
+            .filter(timestamp.le(at))
+            .order((timestamp.desc(), id.desc()))
+            .select((timestamp, value))
+            .first::<(f64, f64)>(&mut self.db)
+            .optional()?;
+        let after = metrics
+            .filter(metric_key_id.eq(key_id))
+            .filter(timestamp.ge(at))
+            .order((timestamp.asc(), id.asc()))
+            .select((timestamp, value))
+            .first::<(f64, f64)>(&mut self.db)
+            .optional()?;
+        let (before, after) = match (before, after) {
+            (Some(before), Some(after)) => (before, after),
+            _ => return Ok(None),
+        };
+        if after.0 == before.0 {
+            return Ok(Some(Value(before.1)));
+        }
+        let value_at = if kind == Some(MetricKind::Counter) {
+            if at - before.0 <= after.0 - at {
+                before.1
+            } else {
+                after.1
             }
-            if flush_counter % 200 == 0 {
-                trace!("Flushing");
-                inner.flush()?;
+        } else {
+            before.1 + (after.1 - before.1) * (at - before.0) / (after.0 - before.0)
+        };
+        Ok(Some(Value(value_at)))
+    }
+
+    /// Returns most recent sample of each given key as `(timestamp, value)` in a single query,
+    /// omitting keys that don't exist or have no samples
+    pub fn latest_values_for(
+        &mut self,
+        keys: &[&str],
+    ) -> Result<HashMap<String, (Timestamp, Value)>> {
+        use crate::schema::metric_keys::dsl::*;
+        let names: HashMap<String, &str> = keys
+            .iter()
+            .map(|name| (self.normalize_key(name).into_owned(), *name))
+            .collect();
+        let name_list = names.keys().collect::<Vec<_>>();
+        let mut ids: HashMap<i64, &str> = HashMap::with_capacity(name_list.len());
+        // one variable of each query binds the empty labels
+        for chunk in name_list.chunks(crate::SQLITE_MAX_VARIABLES - 1) {
+            let found = metric_keys
+                .filter(key.eq_any(chunk).and(labels.eq("")))
+                .select((id, key))
+                .load::<(i64, String)>(&mut self.db)?;
+            ids.extend(
+                found
+                    .into_iter()
+                    .map(|(key_id, name)| (key_id, names[&name])),
+            );
+        }
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let key_ids = ids.keys().copied().collect::<Vec<_>>();
+        let mut rows = Vec::with_capacity(key_ids.len());
+        for chunk in key_ids.chunks(crate::SQLITE_MAX_VARIABLES) {
+            let placeholders = vec!["?"; chunk.len()].join(",");
+            // SQLite takes bare columns from the row holding the MAX()
+            let mut query = sql_query(format!(
+                "SELECT metric_key_id, MAX(timestamp) AS timestamp, value FROM metrics \
+                 WHERE metric_key_id IN ({}) GROUP BY metric_key_id",
+                placeholders
+            ))
+            .into_boxed::<diesel::sqlite::Sqlite>();
+            for key_id in chunk {
+                query = query.bind::<diesel::sql_types::BigInt, _>(*key_id);
+            }
+            rows.extend(query.load::<LatestRow>(&mut self.db)?);
+        }
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                (
+                    ids[&row.metric_key_id].to_string(),
+                    (Timestamp(row.timestamp), Value(row.value)),
+                )
+            })
+            .collect())
+    }
+
+    /// Renders most recent sample of every series in Prometheus text exposition format, with
+    /// `# HELP` & `# TYPE` lines from stored descriptions, units & kinds, e.g. to serve stored
+    /// metrics from a scrape endpoint
+    ///
+    /// Names are sanitized to Prometheus' character set & histograms are rendered as `untyped`,
+    /// being their latest observation rather than a distribution
+    pub fn render_prometheus(&mut self) -> Result<String> {
+        use std::fmt::Write;
+        // SQLite takes bare columns from the row holding the MAX(), labeled series fall back to
+        // details described on their unlabeled key
+        let rows = sql_query(
+            "SELECT k.key, k.labels, COALESCE(k.kind, b.kind) AS kind, \
+             COALESCE(NULLIF(k.unit, ''), b.unit, '') AS unit, \
+             COALESCE(NULLIF(k.description, ''), b.description, '') AS description, \
+             MAX(m.timestamp) AS timestamp, m.value FROM metrics m \
+             JOIN metric_keys k ON k.id = m.metric_key_id \
+             LEFT JOIN metric_keys b ON b.key = k.key AND b.labels = '' \
+             GROUP BY m.metric_key_id ORDER BY k.key, k.labels",
+        )
+        .load::<PrometheusRow>(&mut self.db)?;
+        let mut out = String::new();
+        let mut previous_key = None;
+        for row in &rows {
+            let name = prometheus_name(&row.key);
+            if previous_key != Some(&row.key) {
+                let help = match (row.description.is_empty(), row.unit.is_empty()) {
+                    (false, false) => format!("{} ({})", row.description, row.unit),
+                    (false, true) => row.description.clone(),
+                    (true, false) => row.unit.clone(),
+                    (true, true) => String::new(),
+                };
+                if !help.is_empty() {
+                    let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+                    let _ = writeln!(out, "# HELP {} {}", name, help);
+                }
+                let kind = match row.kind.as_deref() {
+                    Some("counter") => "counter",
+                    Some("gauge") => "gauge",
+                    _ => "untyped",
+                };
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                previous_key = Some(&row.key);
             }
+            let labels = crate::models::parse_labels(&row.labels)
+                .into_iter()
+                .map(|(label, value)| {
+                    let value = value
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    format!("{}=\"{}\"", prometheus_name(&label), value)
+                })
+                .collect::<Vec<_>>();
+            let value = match row.value {
+                v if v == f64::INFINITY => "+Inf".to_string(),
+                v if v == f64::NEG_INFINITY => "-Inf".to_string(),
+                v => v.to_string(),
+            };
+            let labels = if labels.is_empty() {
+                String::new()
+            } else {
+                format!("{{{}}}", labels.join(","))
+            };
+            let _ = writeln!(
+                out,
+                "{}{} {} {}",
+                name,
+                labels,
+                value,
+                (row.timestamp * 1000.0) as i64
+            );
+        }
+        Ok(out)
+    }
+
+    /// Returns the first & last timestamps recorded for given key, useful to spot stale metrics
+    pub fn key_activity(&mut self, key_name: &str) -> Result<(Timestamp, Timestamp)> {
+        use crate::schema::metrics::dsl::*;
+        use diesel::dsl::{max, min};
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let (first_seen, last_seen) = metrics
+            .filter(metric_key_id.eq(metric_key.id))
+            .select((min(timestamp), max(timestamp)))
+            .first::<(Option<Timestamp>, Option<Timestamp>)>(&mut self.db)?;
+        match (first_seen, last_seen) {
+            (Some(first_seen), Some(last_seen)) => Ok((first_seen, last_seen)),
+            _ => Err(MetricsError::NoMetricsForKey(key_name.to_string())),
         }
-        inner.flush()?;
+    }
+
+    /// Sets a user defined attribute (e.g. owning team, display color) on given metric key,
+    /// replacing any existing value for the attribute
+    pub fn set_key_attribute(&mut self, key_name: &str, name: &str, value: &str) -> Result<()> {
+        let metric_key = self.metric_key_for_key(key_name)?;
+        MetricKey::set_attribute(metric_key.id, name, value, &mut self.db)
+    }
+
+    /// Returns all user defined attributes of given metric key
+    pub fn get_key_attributes(&mut self, key_name: &str) -> Result<HashMap<String, String>> {
+        let metric_key = self.metric_key_for_key(key_name)?;
+        MetricKey::attributes(metric_key.id, &mut self.db)
+    }
+
+    /// Returns database metadata entries, e.g. `app_name`, `app_version` & `hostname` stamped by
+    /// the recording `SqliteExporter`
+    pub fn metadata(&mut self) -> Result<HashMap<String, String>> {
+        use crate::schema::db_metadata::dsl::*;
+        let entries = db_metadata
+            .select((name, value))
+            .load::<(String, String)>(&mut self.db)?;
+        Ok(entries.into_iter().collect())
+    }
+
+    /// Attaches a note to given session, e.g. "baseline", keyed by the session's start timestamp
+    pub fn annotate_session(&mut self, session: &Session, text: &str) -> Result<()> {
+        use crate::schema::session_annotations::dsl::*;
+        diesel::insert_into(session_annotations)
+            .values((session_start.eq(session.start_time), note.eq(text)))
+            .execute(&mut self.db)?;
         Ok(())
     }
+
+    /// Returns notes attached to given session in the order they were added
+    pub fn session_annotations(&mut self, session: &Session) -> Result<Vec<String>> {
+        use crate::schema::session_annotations::dsl::*;
+        Ok(session_annotations
+            .filter(session_start.eq(session.start_time))
+            .order(id.asc())
+            .select(note)
+            .load::<String>(&mut self.db)?)
+    }
+
+    /// Returns events recorded with `SqliteExporter::record_event()` with timestamps in
+    /// `[start, end]`, in timestamp order
+    pub fn events_in_range(
+        &mut self,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<RecordedEvent>> {
+        use crate::schema::events::dsl::*;
+        Ok(events
+            .filter(timestamp.between(start, end))
+            .order((timestamp.asc(), id.asc()))
+            .select(RecordedEvent::as_select())
+            .load(&mut self.db)?)
+    }
+
+    /// Checkpoints the WAL, folding it back into the database & truncating the `-wal` file, does
+    /// nothing unless the database is in WAL mode
+    pub fn checkpoint(&mut self) -> Result<()> {
+        Ok(crate::checkpoint(&mut self.db)?)
+    }
+
+    /// Sets function applied to key names given to queries, matching the one given to
+    /// `SqliteExporterBuilder::key_normalizer()` when recording
+    pub fn set_key_normalizer<F>(&mut self, normalizer: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.key_normalizer = Some(Arc::new(normalizer));
+    }
+
+    fn normalize_key<'k>(&self, key_name: &'k str) -> Cow<'k, str> {
+        match &self.key_normalizer {
+            Some(normalizer) => Cow::Owned(normalizer(key_name)),
+            None => Cow::Borrowed(key_name),
+        }
+    }
+
+    fn metric_key_for_key(&mut self, key_name: &str) -> Result<MetricKey<'_>> {
+        self.metric_key_for_labels(key_name, "")
+    }
+
+    fn metric_key_for_labels(&mut self, key_name: &str, key_labels: &str) -> Result<MetricKey<'_>> {
+        use crate::schema::metric_keys::dsl::*;
+        let normalized = self.normalize_key(key_name).into_owned();
+        let query = metric_keys.filter(key.eq(&normalized).and(labels.eq(key_labels)));
+        let found = query.load::<MetricKey>(&mut self.db)?.into_iter().next();
+        if key_labels.is_empty() && self.only_labeled(&normalized, found.as_ref().map(|k| k.id))? {
+            return Err(MetricsError::LabeledKey(key_name.to_string()));
+        }
+        found.ok_or_else(|| {
+            MetricsError::KeyNotFound(crate::models::series_name(key_name, key_labels).into())
+        })
+    }
+
+    /// Returns whether given key has labeled series while its unlabeled one (if any) has no
+    /// samples, so querying it without labels would silently come up empty
+    fn only_labeled(&mut self, key_name: &str, unlabeled_id: Option<i64>) -> Result<bool> {
+        use crate::schema::{metric_keys, metrics};
+        use diesel::dsl::{exists, select};
+        let labeled = metric_keys::table
+            .filter(metric_keys::key.eq(key_name))
+            .filter(metric_keys::labels.ne(""));
+        if !select(exists(labeled)).get_result::<bool>(&mut self.db)? {
+            return Ok(false);
+        }
+        match unlabeled_id {
+            Some(key_id) => {
+                let samples = metrics::table.filter(metrics::metric_key_id.eq(key_id));
+                Ok(!select(exists(samples)).get_result::<bool>(&mut self.db)?)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Returns type given key was registered as, e.g. to render counters as rates, taken from its
+    /// unlabeled series or else any labeled one
+    pub fn kind_for_key(&mut self, key_name: &str) -> Result<MetricKind> {
+        use crate::schema::metric_keys::dsl::*;
+        let normalized = self.normalize_key(key_name).into_owned();
+        let kinds = metric_keys
+            .filter(key.eq(normalized))
+            .order(labels.asc())
+            .select(kind)
+            .load::<Option<MetricKind>>(&mut self.db)?;
+        if kinds.is_empty() {
+            return Err(MetricsError::KeyNotFound(key_name.to_string()));
+        }
+        kinds
+            .into_iter()
+            .flatten()
+            .next()
+            .ok_or_else(|| MetricsError::UnknownKind(key_name.to_string()))
+    }
+
+    /// Returns histogram summaries recorded for given key in ascending timestamp order
+    #[cfg(feature = "histogram_summary")]
+    pub fn histogram_summaries_for_key(
+        &mut self,
+        key_name: &str,
+        session: Option<&Session>,
+    ) -> Result<Vec<(f64, crate::HistogramSummary)>> {
+        use crate::schema::histogram_summaries::dsl::*;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let query = histogram_summaries
+            .select((timestamp, summary))
+            .order(timestamp.asc())
+            .filter(metric_key_id.eq(metric_key.id));
+        let rows = match session {
+            Some(session) => query
+                .filter(timestamp.ge(session.start_time))
+                .filter(timestamp.le(session.end_time))
+                .load::<(f64, String)>(&mut self.db)?,
+            None => query.load::<(f64, String)>(&mut self.db)?,
+        };
+        rows.into_iter()
+            .map(|(ts, json)| Ok((ts, serde_json::from_str(&json)?)))
+            .collect()
+    }
+
+    /// Returns histogram bucket counts stored for given key in ascending timestamp & bound order,
+    /// one row per non-empty bucket per flush
+    pub fn histogram_buckets_for_key(
+        &mut self,
+        key_name: &str,
+        session: Option<&Session>,
+    ) -> Result<Vec<HistogramBucket>> {
+        use crate::schema::histogram_buckets::dsl::*;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let query = histogram_buckets
+            .order((timestamp.asc(), upper_bound.asc()))
+            .filter(metric_key_id.eq(metric_key.id))
+            .select(HistogramBucket::as_select());
+        let r = match session {
+            Some(session) => query
+                .filter(timestamp.ge(session.start_time))
+                .filter(timestamp.le(session.end_time))
+                .load(&mut self.db)?,
+            None => query.load(&mut self.db)?,
+        };
+        Ok(r)
+    }
+
+    /// Resolves keys (creating any that don't exist yet) for given `(key, timestamp, value)`
+    /// samples, returning them ready to insert
+    pub fn resolve_metrics<'k, I>(&mut self, samples: I) -> Result<Vec<NewMetric>>
+    where
+        I: IntoIterator<Item = (&'k str, f64, f64)>,
+    {
+        samples
+            .into_iter()
+            .map(|(key_name, timestamp, value)| {
+                Ok(NewMetric::new(
+                    Timestamp(timestamp),
+                    self.key_ids.id_for(key_name, &mut self.db)?,
+                    Value(value),
+                ))
+            })
+            .collect()
+    }
+
+    /// Inserts given `(key, timestamp, value)` samples in a single transaction, creating any keys
+    /// that don't exist yet, returning number of samples inserted
+    pub fn insert_metrics<'k, I>(&mut self, samples: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (&'k str, f64, f64)>,
+    {
+        use crate::schema::metrics::dsl::metrics;
+        let new_metrics = self.resolve_metrics(samples)?;
+        self.db.transaction::<_, diesel::result::Error, _>(|db| {
+            for metric in &new_metrics {
+                diesel::insert_into(metrics).values(metric).execute(db)?;
+            }
+            Ok(())
+        })?;
+        self.sessions = None;
+        Ok(new_metrics.len())
+    }
+
+    /// Collapses runs of identical consecutive values of given key down to the first & last sample
+    /// of each run, preserving the shape of the series, returning number of samples removed
+    pub fn compact_key(&mut self, key_name: &str) -> Result<usize> {
+        use crate::schema::metrics::dsl::*;
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let samples = metrics
+            .filter(metric_key_id.eq(metric_key.id))
+            .order((timestamp.asc(), id.asc()))
+            .select((id, value))
+            .load::<(i64, f64)>(&mut self.db)?;
+        // anything with an identical neighbour on both sides is interior to a run
+        let redundant: Vec<i64> = samples
+            .windows(3)
+            .filter(|w| w[0].1 == w[1].1 && w[1].1 == w[2].1)
+            .map(|w| w[1].0)
+            .collect();
+        self.db.transaction::<_, diesel::result::Error, _>(|db| {
+            for chunk in redundant.chunks(COMPACT_DELETE_CHUNK) {
+                diesel::delete(metrics.filter(id.eq_any(chunk))).execute(db)?;
+            }
+            Ok(())
+        })?;
+        self.sessions = None;
+        Ok(redundant.len())
+    }
+
+    /// Runs the same house keeping as the exporter on demand, deleting samples older than
+    /// `retention` & the oldest over `record_limit` + 25%, then vacuuming if `vacuum` & a
+    /// retention are given, e.g. to prune a database from tooling without starting an exporter
+    pub fn run_housekeeping(
+        &mut self,
+        retention: Option<Duration>,
+        record_limit: Option<usize>,
+        vacuum: bool,
+    ) -> Result<()> {
+        crate::SqliteExporter::housekeeping(&mut self.db, retention, record_limit, vacuum)?;
+        self.sessions = None;
+        Ok(())
+    }
+
+    /// Deletes samples of every key with timestamps in `[start, end]`, e.g. a window recorded
+    /// during a clock glitch, returning number of samples removed & recalculating sessions
+    pub fn delete_range(&mut self, start: Timestamp, end: Timestamp) -> Result<usize> {
+        self.delete_samples_between(None, start, end)
+    }
+
+    /// Deletes given key, every series of it regardless of labels, along with its samples,
+    /// attributes, archived rollups & histogram data, returning number of samples removed
+    pub fn delete_key(&mut self, key_name: &str) -> Result<usize> {
+        use crate::schema::*;
+        let normalized = self.normalize_key(key_name).into_owned();
+        let key_ids = metric_keys::table
+            .filter(metric_keys::key.eq(&normalized))
+            .select(metric_keys::id)
+            .load::<i64>(&mut self.db)?;
+        if key_ids.is_empty() {
+            return Err(MetricsError::KeyNotFound(key_name.to_string()));
+        }
+        let removed = self.db.transaction::<_, diesel::result::Error, _>(|db| {
+            let removed =
+                diesel::delete(metrics::table.filter(metrics::metric_key_id.eq_any(&key_ids)))
+                    .execute(db)?;
+            diesel::delete(
+                metrics_archive::table.filter(metrics_archive::metric_key_id.eq_any(&key_ids)),
+            )
+            .execute(db)?;
+            diesel::delete(
+                histogram_summaries::table
+                    .filter(histogram_summaries::metric_key_id.eq_any(&key_ids)),
+            )
+            .execute(db)?;
+            diesel::delete(
+                histogram_buckets::table.filter(histogram_buckets::metric_key_id.eq_any(&key_ids)),
+            )
+            .execute(db)?;
+            diesel::delete(
+                metric_key_attributes::table
+                    .filter(metric_key_attributes::metric_key_id.eq_any(&key_ids)),
+            )
+            .execute(db)?;
+            diesel::delete(metric_keys::table.filter(metric_keys::id.eq_any(&key_ids)))
+                .execute(db)?;
+            Ok(removed)
+        })?;
+        self.sessions = None;
+        self.key_ids = KeyIdCache::default();
+        Ok(removed)
+    }
+
+    /// Deletes given key's samples with timestamps in `[start, end]` like `delete_range()`
+    pub fn delete_range_for_key(
+        &mut self,
+        key_name: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<usize> {
+        let key_id = self.metric_key_for_key(key_name)?.id;
+        self.delete_samples_between(Some(key_id), start, end)
+    }
+
+    fn delete_samples_between(
+        &mut self,
+        key_id: Option<i64>,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<usize> {
+        use crate::schema::metrics::dsl::*;
+        let in_range = timestamp.between(start, end);
+        let removed = match key_id {
+            Some(key_id) => diesel::delete(metrics.filter(in_range.and(metric_key_id.eq(key_id))))
+                .execute(&mut self.db)?,
+            None => diesel::delete(metrics.filter(in_range)).execute(&mut self.db)?,
+        };
+        if removed > 0 {
+            self.sessions = None;
+        }
+        Ok(removed)
+    }
+
+    /// Deletes all samples, keeping keys along with their units, descriptions & attributes, then
+    /// vacuums to reclaim space
+    pub fn truncate_samples(&mut self) -> Result<()> {
+        self.db.transaction::<_, diesel::result::Error, _>(|db| {
+            diesel::delete(crate::schema::metrics::table).execute(db)?;
+            diesel::delete(crate::schema::histogram_summaries::table).execute(db)?;
+            diesel::delete(crate::schema::metrics_archive::table).execute(db)?;
+            diesel::delete(crate::schema::histogram_buckets::table).execute(db)?;
+            Ok(())
+        })?;
+        sql_query("VACUUM").execute(&mut self.db)?;
+        self.sessions = None;
+        Ok(())
+    }
+
+    /// Deletes everything, samples, keys, session annotations, events & database metadata, then
+    /// vacuums to reclaim space
+    pub fn clear_all(&mut self) -> Result<()> {
+        use crate::schema::*;
+        self.db.transaction::<_, diesel::result::Error, _>(|db| {
+            diesel::delete(metrics::table).execute(db)?;
+            diesel::delete(histogram_summaries::table).execute(db)?;
+            diesel::delete(histogram_buckets::table).execute(db)?;
+            diesel::delete(metric_key_attributes::table).execute(db)?;
+            diesel::delete(metric_keys::table).execute(db)?;
+            diesel::delete(db_metadata::table).execute(db)?;
+            diesel::delete(session_annotations::table).execute(db)?;
+            diesel::delete(metrics_archive::table).execute(db)?;
+            diesel::delete(events::table).execute(db)?;
+            Ok(())
+        })?;
+        sql_query("VACUUM").execute(&mut self.db)?;
+        self.sessions = None;
+        self.key_ids = KeyIdCache::default();
+        Ok(())
+    }
+
+    /// Writes a vacuumed, compacted copy of the database to `dest` using `VACUUM INTO`, leaving
+    /// this database untouched
+    ///
+    /// Fails if `dest` already exists and isn't empty
+    pub fn vacuum_into<P: AsRef<Path>>(&mut self, dest: P) -> Result<()> {
+        use diesel::sql_types::Text;
+        let dest = dest
+            .as_ref()
+            .to_str()
+            .ok_or(MetricsError::InvalidDatabasePath)?;
+        sql_query("VACUUM INTO ?")
+            .bind::<Text, _>(dest)
+            .execute(&mut self.db)?;
+        Ok(())
+    }
+
+    /// Deletes samples whose value is NaN or infinite, which break aggregate queries, returning
+    /// number of samples removed
+    ///
+    /// SQLite binds NaN as NULL, which the `value` column rejects, so the exporter drops NaN
+    /// samples before storing them & only databases written by other tools can hold them
+    pub fn scrub_non_finite(&mut self) -> Result<usize> {
+        let removed = sql_query(
+            "DELETE FROM metrics WHERE value IS NULL OR value != value OR abs(value) >= 9e999",
+        )
+        .execute(&mut self.db)?;
+        self.sessions = None;
+        Ok(removed)
+    }
+
+    /// Counts samples whose key ID has no matching key entry, e.g. left behind by manual edits,
+    /// which joins on key names silently skip
+    ///
+    /// Foreign keys are enforced on connections this crate opens, so new orphans only come from
+    /// other tools or predate enforcement. Orphans from before are kept when upgrading, but can't
+    /// be updated until their key exists or they're deleted.
+    pub fn find_orphan_samples(&mut self) -> Result<u64> {
+        use crate::schema::metric_keys;
+        use crate::schema::metrics::dsl::*;
+        let orphans = metrics
+            .filter(metric_key_id.ne_all(metric_keys::table.select(metric_keys::id)))
+            .count()
+            .get_result::<i64>(&mut self.db)?;
+        Ok(orphans as u64)
+    }
+
+    /// Deletes samples found by `find_orphan_samples()`, returning number of samples removed
+    pub fn delete_orphan_samples(&mut self) -> Result<u64> {
+        use crate::schema::metric_keys;
+        use crate::schema::metrics::dsl::*;
+        let removed = diesel::delete(
+            metrics.filter(metric_key_id.ne_all(metric_keys::table.select(metric_keys::id))),
+        )
+        .execute(&mut self.db)?;
+        self.sessions = None;
+        Ok(removed as u64)
+    }
+
+    /// Returns number of keys first seen per UTC day as `(day_start, new_keys)` in ascending order,
+    /// skipping days without new keys
+    ///
+    /// A spike usually means keys with dynamic names were introduced, bloating the database
+    pub fn key_creation_timeline(&mut self) -> Result<Vec<(Timestamp, u64)>> {
+        let rows = sql_query(
+            "SELECT CAST(first_seen / 86400 AS INTEGER) * 86400.0 AS day, COUNT(*) AS keys \
+             FROM (SELECT MIN(timestamp) AS first_seen FROM metrics GROUP BY metric_key_id) \
+             GROUP BY day ORDER BY day",
+        )
+        .load::<DayCountRow>(&mut self.db)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (Timestamp(row.day), row.keys as u64))
+            .collect())
+    }
+
+    /// Returns series as `(key, labels)` whose samples within the last `within` all have the same
+    /// value, e.g. a stuck sensor that's still reporting. Series need at least 2 samples in the
+    /// window to be considered.
+    ///
+    /// Labels are as stored, sorted `name=value` pairs joined by `,`, empty if unlabeled
+    pub fn stuck_keys(&mut self, within: Duration) -> Result<Vec<(String, String)>> {
+        use diesel::sql_types::Double;
+        let cutoff = SystemTime::UNIX_EPOCH
+            .elapsed()
+            .map(|now| now.saturating_sub(within).as_secs_f64())
+            .unwrap_or(0.0);
+        let keys = sql_query(
+            "SELECT metric_keys.key AS key, metric_keys.labels AS labels FROM metrics \
+             INNER JOIN metric_keys ON metric_keys.id = metrics.metric_key_id \
+             WHERE metrics.timestamp >= ? \
+             GROUP BY metrics.metric_key_id \
+             HAVING COUNT(*) > 1 AND MIN(metrics.value) = MAX(metrics.value) \
+             ORDER BY metric_keys.key, metric_keys.labels",
+        )
+        .bind::<Double, _>(cutoff)
+        .load::<SeriesRow>(&mut self.db)?;
+        Ok(keys.into_iter().map(|row| (row.key, row.labels)).collect())
+    }
+
+    /// Returns each session labelled with the series as `(key, labels)` that has the most samples
+    /// in it, ties going to the alphabetically first series
+    pub fn session_dominant_keys(&mut self) -> Result<Vec<(Session, (String, String))>> {
+        use diesel::sql_types::Double;
+        let sessions = self.cached_sessions()?.clone();
+        let mut labelled = Vec::with_capacity(sessions.len());
+        for session in &sessions {
+            let dominant = sql_query(
+                "SELECT metric_keys.key AS key, metric_keys.labels AS labels FROM metrics \
+                 INNER JOIN metric_keys ON metric_keys.id = metrics.metric_key_id \
+                 WHERE metrics.timestamp >= ? AND metrics.timestamp <= ? \
+                 GROUP BY metrics.metric_key_id \
+                 ORDER BY COUNT(*) DESC, metric_keys.key, metric_keys.labels \
+                 LIMIT 1",
+            )
+            .bind::<Double, _>(session.start_time)
+            .bind::<Double, _>(session.end_time)
+            .get_result::<SeriesRow>(&mut self.db)
+            .optional()?;
+            if let Some(row) = dominant {
+                labelled.push((*session, (row.key, row.labels)));
+            }
+        }
+        Ok(labelled)
+    }
+
+    /// Returns each session with its number of distinct timestamps, roughly how many flush ticks
+    /// it had. Few ticks for a session's length hints at an overloaded recorder.
+    pub fn session_tick_counts(&mut self) -> Result<Vec<(Session, u64)>> {
+        use crate::schema::metrics::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::sql_types::BigInt;
+        let sessions = self.cached_sessions()?.clone();
+        let mut counts = Vec::with_capacity(sessions.len());
+        for session in &sessions {
+            let ticks = metrics
+                .filter(timestamp.ge(session.start_time))
+                .filter(timestamp.le(session.end_time))
+                .select(sql::<BigInt>("COUNT(DISTINCT timestamp)"))
+                .first::<i64>(&mut self.db)?;
+            counts.push((*session, ticks as u64));
+        }
+        Ok(counts)
+    }
+
+    /// Returns aggregate of given key's samples from the last `window` up to now, computed in SQL,
+    /// None if there's no data in the window
+    pub fn windowed_aggregate(
+        &mut self,
+        key_name: &str,
+        window: Duration,
+        agg: BucketAgg,
+    ) -> Result<Option<f64>> {
+        use crate::schema::metrics::dsl::*;
+        use diesel::dsl::{avg, count_star, max, min, sum};
+        let metric_key = self.metric_key_for_key(key_name)?;
+        let cutoff = SystemTime::UNIX_EPOCH
+            .elapsed()
+            .map(|now| now.saturating_sub(window).as_secs_f64())
+            .unwrap_or(0.0);
+        let query = metrics
+            .filter(metric_key_id.eq(metric_key.id))
+            .filter(timestamp.ge(cutoff));
+        let r = match agg {
+            BucketAgg::Mean => query.select(avg(value)).first(&mut self.db)?,
+            BucketAgg::Min => query.select(min(value)).first(&mut self.db)?,
+            BucketAgg::Max => query.select(max(value)).first(&mut self.db)?,
+            BucketAgg::Sum => query.select(sum(value)).first(&mut self.db)?,
+            BucketAgg::Count => match query.select(count_star()).first::<i64>(&mut self.db)? {
+                0 => None,
+                samples => Some(samples as f64),
+            },
+        };
+        Ok(r)
+    }
+
+    /// Returns rate of change, the derivative, of the given metrics key's values
+    ///
+    /// f(t) = (x(t + 1) - x(t)) / ((t+1) - (t)
+    pub fn deriv_metrics_for_key(
+        &mut self,
+        key_name: &str,
+        session: Option<&Session>,
+    ) -> Result<Vec<DerivMetric>> {
+        let m = self.metrics_for_key(key_name, session)?;
+        let new_values: Vec<_> = m
+            .windows(2)
+            .map(|v| {
+                let new_value =
+                    (v[1].value.0 - v[0].value.0) / (v[1].timestamp.0 - v[0].timestamp.0);
+                DerivMetric {
+                    timestamp: v[1].timestamp,
+                    key: format!("{}.deriv", key_name),
+                    value: Value(new_value),
+                }
+            })
+            .collect();
+        Ok(new_values)
+    }
+
+    /// Returns per-sample increments of given counter key as `(timestamp, increment)`, since
+    /// counters are stored as cumulative totals
+    ///
+    /// A value lower than the previous one is treated as a counter reset, its increment being the
+    /// value itself
+    pub fn counter_increments_for_key(
+        &mut self,
+        key_name: &str,
+        session: Option<&Session>,
+    ) -> Result<Vec<(Timestamp, Value)>> {
+        let m = self.metrics_for_key(key_name, session)?;
+        let increments = m
+            .windows(2)
+            .map(|v| {
+                let increment = if v[1].value < v[0].value {
+                    v[1].value
+                } else {
+                    Value(v[1].value.0 - v[0].value.0)
+                };
+                (v[1].timestamp, increment)
+            })
+            .collect();
+        Ok(increments)
+    }
+
+    /// Returns distribution of intervals between consecutive samples of given key, bucketed by
+    /// given ascending upper bounds
+    ///
+    /// Result has one count per bucket plus a final count of intervals at or above the last bound,
+    /// e.g. bounds of `[1s, 5s]` count intervals `< 1s`, `1s..5s` & `>= 5s`
+    pub fn cadence_histogram_for_key(
+        &mut self,
+        key_name: &str,
+        buckets: &[Duration],
+        session: Option<&Session>,
+    ) -> Result<Vec<u64>> {
+        let m = self.metrics_for_key(key_name, session)?;
+        let mut counts = vec![0u64; buckets.len() + 1];
+        for pair in m.windows(2) {
+            let interval = pair[1].timestamp.0 - pair[0].timestamp.0;
+            let bucket = buckets
+                .iter()
+                .position(|bound| interval < bound.as_secs_f64())
+                .unwrap_or(buckets.len());
+            counts[bucket] += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Returns `(gap_start, gap_end)` of every interval between consecutive samples of given key
+    /// longer than `expected_interval`, i.e. where data went missing
+    pub fn gaps_for_key(
+        &mut self,
+        key_name: &str,
+        expected_interval: Duration,
+        session: Option<&Session>,
+    ) -> Result<Vec<(Timestamp, Timestamp)>> {
+        let expected = expected_interval.as_secs_f64();
+        let m = self.metrics_for_key(key_name, session)?;
+        let gaps = m
+            .windows(2)
+            .filter(|pair| pair[1].timestamp.0 - pair[0].timestamp.0 > expected)
+            .map(|pair| (pair[0].timestamp, pair[1].timestamp))
+            .collect();
+        Ok(gaps)
+    }
+
+    /// Compares given key's unlabeled series against the same series in another database, e.g.
+    /// captures from before & after a change
+    ///
+    /// Runs are usually recorded at different times so series are aligned by time since each one's
+    /// first sample, pairing every sample here with the nearest one in `other`
+    pub fn diff<P: AsRef<Path>>(&mut self, other: P, key_name: &str) -> Result<Vec<DiffPoint>> {
+        use diesel::sql_types::Text;
+        let ours = self.metrics_for_key(key_name, None)?;
+        // own connection rather than ATTACH, which SQLite refuses within a snapshot's transaction
+        let mut other = Self::establish_read_only(other.as_ref())?;
+        let theirs = sql_query(
+            "SELECT m.timestamp AS timestamp, m.value AS value FROM metrics m \
+             INNER JOIN metric_keys k ON k.id = m.metric_key_id \
+             WHERE k.key = ? AND k.labels = '' ORDER BY m.timestamp, m.id",
+        )
+        .bind::<Text, _>(self.normalize_key(key_name).as_ref())
+        .load::<SampleRow>(&mut other)?;
+        let (ours_start, theirs_start) = match (ours.first(), theirs.first()) {
+            (Some(ours), Some(theirs)) => (ours.timestamp.0, theirs.timestamp),
+            _ => return Ok(Vec::new()),
+        };
+        let mut points = Vec::with_capacity(ours.len());
+        let mut nearest = 0;
+        for metric in &ours {
+            let offset = metric.timestamp.0 - ours_start;
+            let their_offset = |i: usize| theirs[i].timestamp - theirs_start;
+            while nearest + 1 < theirs.len()
+                && (their_offset(nearest + 1) - offset).abs()
+                    <= (their_offset(nearest) - offset).abs()
+            {
+                nearest += 1;
+            }
+            let other_value = theirs[nearest].value;
+            points.push(DiffPoint {
+                offset,
+                value: metric.value,
+                other_value: Value(other_value),
+                difference: other_value - metric.value.0,
+            });
+        }
+        Ok(points)
+    }
+
+    /// Returns pointwise ratio of two keys' series as `(timestamp, numerator / denominator)`, e.g.
+    /// an error rate from error & request counters
+    ///
+    /// Each numerator sample is paired with the denominator sample nearest in time, points whose
+    /// denominator is zero are skipped
+    pub fn ratio_of_keys(
+        &mut self,
+        numerator: &str,
+        denominator: &str,
+        session: Option<&Session>,
+    ) -> Result<Vec<(Timestamp, Value)>> {
+        let numerators = self.metrics_for_key(numerator, session)?;
+        let denominators = self.metrics_for_key(denominator, session)?;
+        let mut ratios = Vec::with_capacity(numerators.len());
+        let mut nearest = 0;
+        for n in &numerators {
+            // both series are ascending so the nearest denominator only moves forward
+            while nearest + 1 < denominators.len()
+                && (denominators[nearest + 1].timestamp.0 - n.timestamp.0).abs()
+                    <= (denominators[nearest].timestamp.0 - n.timestamp.0).abs()
+            {
+                nearest += 1;
+            }
+            match denominators.get(nearest) {
+                Some(d) if d.value.0 != 0.0 => {
+                    ratios.push((n.timestamp, Value(n.value.0 / d.value.0)))
+                }
+                _ => {}
+            }
+        }
+        Ok(ratios)
+    }
+
+    /// Returns given keys' series resampled onto a shared time axis, for correlation & plotting
+    ///
+    /// Axis starts at the session's start (or earliest sample of given keys) & advances by `step`
+    /// up to its end. Each key's value at a point is the mean of its samples from that point up to
+    /// the next, None when it has none, with one series per key in given order.
+    pub fn aligned_series(
+        &mut self,
+        keys: &[&str],
+        step: Duration,
+        session: Option<&Session>,
+    ) -> Result<AlignedSeries> {
+        if step.is_zero() {
+            return Err(MetricsError::InvalidArgument(
+                "step must be greater than zero",
+            ));
+        }
+        let step = step.as_secs_f64();
+        let series = keys
+            .iter()
+            .map(|key_name| self.metrics_for_key(key_name, session))
+            .collect::<Result<Vec<_>>>()?;
+        let bounds = match session {
+            Some(session) => Some((session.start_time, session.end_time)),
+            None => series
+                .iter()
+                .flat_map(|m| m.first().zip(m.last()))
+                .map(|(first, last)| (first.timestamp.0, last.timestamp.0))
+                .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1))),
+        };
+        let (start, end) = match bounds {
+            Some(bounds) => bounds,
+            None => return Ok((Vec::new(), vec![Vec::new(); keys.len()])),
+        };
+        let points = ((end - start) / step).floor() as usize + 1;
+        let axis = (0..points)
+            .map(|i| Timestamp(start + i as f64 * step))
+            .collect();
+        let values = series
+            .iter()
+            .map(|m| {
+                let mut sums = vec![(0.0, 0usize); points];
+                for metric in m {
+                    let i = (((metric.timestamp.0 - start) / step) as usize).min(points - 1);
+                    sums[i].0 += metric.value.0;
+                    sums[i].1 += 1;
+                }
+                sums.into_iter()
+                    .map(|(sum, count)| (count > 0).then(|| Value(sum / count as f64)))
+                    .collect()
+            })
+            .collect();
+        Ok((axis, values))
+    }
+
+    /// Streams every sample in the database as `(key, labels, timestamp, value)` in global
+    /// timestamp order, without loading the whole table into memory
+    pub fn all_metrics_ordered(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<(String, String, Timestamp, Value)>> + '_> {
+        Self::ordered_samples(&mut self.db)
+    }
+
+    pub(crate) fn ordered_samples(
+        db: &mut SqliteConnection,
+    ) -> Result<impl Iterator<Item = Result<(String, String, Timestamp, Value)>> + '_> {
+        use crate::schema::metric_keys::dsl::{key, labels};
+        use crate::schema::metrics::dsl::*;
+        use diesel::connection::DefaultLoadingMode;
+        let rows = crate::schema::metrics::table
+            .inner_join(crate::schema::metric_keys::table)
+            .order((timestamp.asc(), id.asc()))
+            .select((key, labels, timestamp, value))
+            .load_iter::<(String, String, Timestamp, Value), DefaultLoadingMode>(db)?;
+        Ok(rows.map(|row| row.map_err(MetricsError::from)))
+    }
+
+    /// Returns samples joined with their key names & labels in timestamp order, optionally limited
+    /// to a session
+    pub fn joined_metrics(&mut self, session: Option<&Session>) -> Result<Vec<JoinedMetric>> {
+        use crate::schema::metric_keys::dsl::{key, labels};
+        use crate::schema::metrics::dsl::*;
+        let mut query = crate::schema::metrics::table
+            .inner_join(crate::schema::metric_keys::table)
+            .order((timestamp.asc(), id.asc()))
+            .select((id, timestamp, key, labels, value))
+            .into_boxed();
+        if let Some(session) = session {
+            query = query.filter(timestamp.between(session.start_time, session.end_time));
+        }
+        Ok(query.load::<JoinedMetric>(&mut self.db)?)
+    }
+
+    /// Exports every sample to a JSON Lines file, one `{"t":timestamp,"k":"key","l":"labels",
+    /// "v":value}` object per line in timestamp order, streaming rows so memory use stays flat
+    ///
+    /// Non-finite values are written as `null`
+    #[cfg(feature = "export_jsonl")]
+    pub fn export_to_jsonl<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        use std::io::Write;
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for row in self.all_metrics_ordered()? {
+            let (k, l, t, v) = row?;
+            serde_json::to_writer(
+                &mut out,
+                &JsonlMetric {
+                    t: t.0,
+                    k: &k,
+                    l: &l,
+                    v: v.0,
+                },
+            )?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Exports every sample to a JSON file as an array of `{"timestamp":t,"key":"k","labels":"l",
+    /// "value":v}` objects in timestamp order, streaming rows so memory use stays flat
+    ///
+    /// Non-finite values are written as `null`
+    #[cfg(feature = "export_json")]
+    pub fn export_to_json<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        use std::io::Write;
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        out.write_all(b"[")?;
+        for (i, row) in self.all_metrics_ordered()?.enumerate() {
+            let (key, labels, timestamp, value) = row?;
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            serde_json::to_writer(
+                &mut out,
+                &JsonMetric {
+                    timestamp: timestamp.0,
+                    key: &key,
+                    labels: &labels,
+                    value: value.0,
+                },
+            )?;
+        }
+        out.write_all(b"]")?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Exports DB contents to CSV file
+    #[cfg(feature = "export_csv")]
+    pub fn export_to_csv<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.export_csv(path, None, &CsvExportOptions::default())
+    }
+    /// Exports DB contents to CSV file like `export_to_csv()`, formatted with given options
+    #[cfg(feature = "export_csv")]
+    pub fn export_to_csv_with_options<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: &CsvExportOptions,
+    ) -> Result<()> {
+        self.export_csv(path, None, options)
+    }
+    /// Exports samples with timestamps in `[start, end]` to CSV file, filtering in SQL & streaming
+    /// rows so memory use stays flat regardless of database size
+    #[cfg(feature = "export_csv")]
+    pub fn export_range_to_csv<P: AsRef<Path>>(
+        &mut self,
+        start: Timestamp,
+        end: Timestamp,
+        path: P,
+    ) -> Result<()> {
+        self.export_csv(path, Some((start, end)), &CsvExportOptions::default())
+    }
+    #[cfg(feature = "export_csv")]
+    fn export_csv<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        range: Option<(Timestamp, Timestamp)>,
+        options: &CsvExportOptions,
+    ) -> Result<()> {
+        use crate::schema::metric_keys::dsl::{key, labels};
+        use crate::schema::metrics::dsl::*;
+        use diesel::connection::DefaultLoadingMode;
+        use std::fs::File;
+        let out_file = File::create(path)?;
+        let mut csv_writer = csv::WriterBuilder::new()
+            .delimiter(options.delimiter)
+            .quote_style(options.quote_style)
+            .terminator(options.terminator)
+            .from_writer(out_file);
+        // join the 2 tables so we get a flat CSV with the actual key names
+        let query = crate::schema::metrics::table.inner_join(crate::schema::metric_keys::table);
+        let mut query = query
+            .order(timestamp.asc())
+            .select((id, timestamp, key, labels, value))
+            .into_boxed();
+        if let Some((start, end)) = range {
+            query = query.filter(timestamp.between(start, end));
+        }
+        for row in query.load_iter::<JoinedMetric, DefaultLoadingMode>(&mut self.db)? {
+            csv_writer.serialize(row?)?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+    /// Imports CSV file into a MetricsDb file, skipping rows that fail to parse or record
+    ///
+    /// Samples are inserted alongside already stored ones of the same key & timestamp, see
+    /// `import_from_csv_with_conflict()` to skip or replace those instead.
+    /// Returns counts of rows read, inserted & skipped
+    #[cfg(feature = "import_csv")]
+    pub fn import_from_csv<S: AsRef<Path>, D: AsRef<Path>>(
+        path: S,
+        destination: D,
+    ) -> Result<ImportStats> {
+        Self::import_from_csv_with_conflict(path, destination, OnConflict::Insert)
+    }
+    /// Imports CSV file into a MetricsDb file like `import_from_csv()`, resolving rows whose key &
+    /// timestamp already exist in the database as given, making re-running imports idempotent
+    ///
+    /// Rows skipped as duplicates are counted in `ImportStats::skipped`
+    #[cfg(feature = "import_csv")]
+    pub fn import_from_csv_with_conflict<S: AsRef<Path>, D: AsRef<Path>>(
+        path: S,
+        destination: D,
+        on_conflict: OnConflict,
+    ) -> Result<ImportStats> {
+        use crate::InnerState;
+        use csv::ReaderBuilder;
+        let db = setup_db(destination)?;
+        let mut reader = ReaderBuilder::new().from_path(path)?;
+        let mut inner = InnerState::new(Duration::from_secs(5), db);
+        let header = reader.headers()?.to_owned();
+        let mut stats = ImportStats::default();
+        let mut queued = 0u64;
+        for record in reader.records() {
+            stats.read += 1;
+            match record {
+                Ok(record) => match record.deserialize::<MetricCsvRow>(Some(&header)) {
+                    Ok(r) => {
+                        match inner.queue_metric(
+                            Duration::from_secs_f64(r.timestamp),
+                            r.key,
+                            r.labels,
+                            r.value,
+                        ) {
+                            Ok(()) => queued += 1,
+                            Err(e) => {
+                                error!(
+                                    "Skipping record due to error recording metric into DB: {:?}",
+                                    e
+                                );
+                                stats.skipped += 1;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("Skipping record due to error parsing CSV row: {:?}", e);
+                        stats.skipped += 1;
+                    }
+                },
+                Err(e) => {
+                    error!("Skipping record due to error reading CSV record: {:?}", e);
+                    stats.skipped += 1;
+                }
+            }
+            if stats.read % 200 == 0 {
+                trace!("Flushing");
+                let written = inner.flush_resolving(on_conflict)? as u64;
+                stats.inserted += written;
+                stats.skipped += queued - written;
+                queued = 0;
+            }
+        }
+        let written = inner.flush_resolving(on_conflict)? as u64;
+        stats.inserted += written;
+        stats.skipped += queued - written;
+        Ok(stats)
+    }
+}
+/// Formatting options for `MetricsDb::export_to_csv_with_options()`, defaults match
+/// `export_to_csv()`
+#[cfg(feature = "export_csv")]
+#[derive(Debug, Copy, Clone)]
+pub struct CsvExportOptions {
+    /// Field delimiter, `b','` by default
+    pub delimiter: u8,
+    /// When fields are quoted, only when necessary by default
+    pub quote_style: csv::QuoteStyle,
+    /// Record terminator, `\n` by default
+    pub terminator: csv::Terminator,
+}
+#[cfg(feature = "export_csv")]
+impl Default for CsvExportOptions {
+    fn default() -> Self {
+        CsvExportOptions {
+            delimiter: b',',
+            quote_style: csv::QuoteStyle::Necessary,
+            terminator: csv::Terminator::Any(b'\n'),
+        }
+    }
 }
-#[cfg(feature = "import_csv")]
-#[derive(Deserialize)]
-struct MetricCsvRow<'a> {
-    #[allow(unused)]
-    id: u64,
-    timestamp: f64,
-    key: &'a str,
-    value: f64,
-}
-/// Metric model for CSV export
 #[cfg(feature = "export_csv")]
-#[derive(Queryable, Debug)]
+impl CsvExportOptions {
+    /// Sets field delimiter, e.g. `b';'` for tools expecting European locale CSV
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+    /// Sets when fields are quoted
+    pub fn quote_style(mut self, quote_style: csv::QuoteStyle) -> Self {
+        self.quote_style = quote_style;
+        self
+    }
+    /// Sets record terminator, `csv::Terminator::CRLF` for `\r\n`
+    pub fn terminator(mut self, terminator: csv::Terminator) -> Self {
+        self.terminator = terminator;
+        self
+    }
+}
+/// How samples whose key & timestamp already exist in the database are handled, e.g. by CSV
+/// import
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Insert alongside already stored samples of its key & timestamp
+    Insert,
+    /// Keep existing row, skipping imported one
+    Skip,
+    /// Replace existing row's value with imported one
+    Replace,
+}
+/// Row counts from `MetricsDb::import_from_csv()`
+#[cfg(feature = "import_csv")]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ImportStats {
+    /// Rows read from CSV file
+    pub read: u64,
+    /// Rows inserted (or replaced) into database, as counted by the flushes writing them
+    pub inserted: u64,
+    /// Rows skipped due to parse or DB errors, or as duplicates
+    pub skipped: u64,
+}
+/// Shared time axis & per key values on it, from `MetricsDb::aligned_series()`
+pub type AlignedSeries = (Vec<Timestamp>, Vec<Vec<Option<Value>>>);
+/// Timestamp & value row from raw SQL queries
+#[derive(QueryableByName)]
+struct SampleRow {
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    timestamp: f64,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    value: f64,
+}
+/// What a unit measures, only units of the same dimension convert to each other
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum UnitDimension {
+    Count,
+    Percent,
+    Time,
+    Data,
+    DataRate,
+    CountRate,
+}
+/// Returns unit's dimension & size in the dimension's base unit (seconds, bytes or bits/second)
+fn unit_scale(unit: Unit) -> (UnitDimension, f64) {
+    const KIB: f64 = 1024.0;
+    match unit {
+        Unit::Count => (UnitDimension::Count, 1.0),
+        Unit::Percent => (UnitDimension::Percent, 1.0),
+        Unit::Seconds => (UnitDimension::Time, 1.0),
+        Unit::Milliseconds => (UnitDimension::Time, 1e-3),
+        Unit::Microseconds => (UnitDimension::Time, 1e-6),
+        Unit::Nanoseconds => (UnitDimension::Time, 1e-9),
+        Unit::Bytes => (UnitDimension::Data, 1.0),
+        Unit::Kibibytes => (UnitDimension::Data, KIB),
+        Unit::Mebibytes => (UnitDimension::Data, KIB * KIB),
+        Unit::Gigibytes => (UnitDimension::Data, KIB * KIB * KIB),
+        Unit::Tebibytes => (UnitDimension::Data, KIB * KIB * KIB * KIB),
+        Unit::BitsPerSecond => (UnitDimension::DataRate, 1.0),
+        Unit::KilobitsPerSecond => (UnitDimension::DataRate, 1e3),
+        Unit::MegabitsPerSecond => (UnitDimension::DataRate, 1e6),
+        Unit::GigabitsPerSecond => (UnitDimension::DataRate, 1e9),
+        Unit::TerabitsPerSecond => (UnitDimension::DataRate, 1e12),
+        Unit::CountPerSecond => (UnitDimension::CountRate, 1.0),
+    }
+}
+/// Latest sample row from `latest_values_for()`
+#[derive(QueryableByName)]
+struct LatestRow {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    metric_key_id: i64,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    timestamp: f64,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    value: f64,
+}
+/// Latest sample & key details row from `render_prometheus()`
+#[derive(QueryableByName)]
+struct PrometheusRow {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    key: String,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    labels: String,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+    kind: Option<String>,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    unit: String,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    description: String,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    timestamp: f64,
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    value: f64,
+}
+/// Replaces characters Prometheus doesn't allow in metric & label names with `_`
+fn prometheus_name(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'a'..='z' | 'A'..='Z' | '_' | ':' => c,
+            '0'..='9' if i > 0 => c,
+            _ => '_',
+        })
+        .collect()
+}
+/// Result row of `PRAGMA journal_mode`
+#[derive(QueryableByName)]
+struct JournalModeRow {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    journal_mode: String,
+}
+/// Day & count row from `key_creation_timeline()`
+#[derive(QueryableByName)]
+struct DayCountRow {
+    #[diesel(sql_type = diesel::sql_types::Double)]
+    day: f64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    keys: i64,
+}
+/// Key name & labels row from raw SQL queries
+#[derive(QueryableByName)]
+struct SeriesRow {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    key: String,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    labels: String,
+}
+#[cfg(feature = "import_csv")]
+#[derive(Deserialize)]
+struct MetricCsvRow<'a> {
+    #[allow(unused)]
+    id: u64,
+    timestamp: f64,
+    key: &'a str,
+    /// missing from CSV files exported before labels were
+    #[serde(default)]
+    labels: &'a str,
+    value: f64,
+}
+/// Sample annotated with its key name & labels, as returned by `MetricsDb::joined_metrics()`
+#[derive(Queryable, Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize))]
-struct JoinedMetric {
+pub struct JoinedMetric {
     /// Unique ID of sample
     pub id: i64,
     /// Timestamp of sample
-    pub timestamp: f64,
+    pub timestamp: Timestamp,
     /// Key/name of sample
     pub key: String,
+    /// Labels of sample's series as stored, sorted `name=value` pairs joined by `,`, empty if
+    /// unlabeled
+    pub labels: String,
     /// Value of sample
-    pub value: f64,
+    pub value: Value,
+}
+/// Sample line for JSON Lines export
+#[cfg(feature = "export_jsonl")]
+#[derive(serde::Serialize)]
+struct JsonlMetric<'a> {
+    t: f64,
+    k: &'a str,
+    l: &'a str,
+    v: f64,
+}
+/// Sample object for JSON export
+#[cfg(feature = "export_json")]
+#[derive(serde::Serialize)]
+struct JsonMetric<'a> {
+    timestamp: f64,
+    key: &'a str,
+    labels: &'a str,
+    value: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InnerState;
+    use std::path::PathBuf;
+
+    /// Database path in its own temporary directory, removed along with it when dropped
+    struct TestDbPath {
+        _dir: tempfile::TempDir,
+        path: PathBuf,
+    }
+    impl std::ops::Deref for TestDbPath {
+        type Target = Path;
+        fn deref(&self) -> &Path {
+            &self.path
+        }
+    }
+    impl AsRef<Path> for TestDbPath {
+        fn as_ref(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    fn test_db_path(name: &str) -> TestDbPath {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("{}.db", name));
+        TestDbPath { _dir: dir, path }
+    }
+
+    fn populate(path: &Path, samples: &[(&str, f64, f64)]) {
+        let db = setup_db(path).unwrap();
+        let mut state = InnerState::new(Duration::from_secs(5), db);
+        for (key, timestamp, value) in samples {
+            state
+                .queue_metric(Duration::from_secs_f64(*timestamp), key, "", *value)
+                .unwrap();
+        }
+        state.flush().unwrap();
+    }
+
+    #[test]
+    fn test_key_activity() {
+        let path = test_db_path("key-activity");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("b", 12.0, 1.0),
+                ("a", 15.0, 2.0),
+                ("a", 20.0, 3.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(
+            db.key_activity("a").unwrap(),
+            (Timestamp(10.0), Timestamp(20.0))
+        );
+        assert_eq!(
+            db.key_activity("b").unwrap(),
+            (Timestamp(12.0), Timestamp(12.0))
+        );
+        assert!(matches!(
+            db.key_activity("missing"),
+            Err(MetricsError::KeyNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_session_downtime() {
+        let path = test_db_path("session-downtime");
+        populate(
+            &path,
+            &[
+                ("a", 0.0, 1.0),
+                ("a", 10.0, 1.0),
+                ("a", 60.0, 1.0),
+                ("a", 70.0, 1.0),
+                ("a", 10_000.0, 1.0),
+                ("a", 10_010.0, 1.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.set_session_thresholds(SessionThresholds {
+            pause: Duration::from_secs(30),
+            downtime: Some(Duration::from_secs(60 * 60)),
+        })
+        .unwrap();
+        let gaps: Vec<_> = db
+            .sessions()
+            .unwrap()
+            .iter()
+            .map(|s| s.preceded_by)
+            .collect();
+        assert_eq!(
+            gaps,
+            vec![None, Some(SessionGap::Pause), Some(SessionGap::Downtime)]
+        );
+    }
+
+    #[test]
+    fn test_key_attributes() {
+        let path = test_db_path("key-attributes");
+        populate(&path, &[("a", 10.0, 1.0), ("a", 15.0, 2.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.set_key_attribute("a", "team", "video").unwrap();
+        db.set_key_attribute("a", "color", "red").unwrap();
+        db.set_key_attribute("a", "color", "blue").unwrap();
+        let attributes = db.get_key_attributes("a").unwrap();
+        assert_eq!(attributes.len(), 2);
+        assert_eq!(attributes["team"], "video");
+        assert_eq!(attributes["color"], "blue");
+        assert!(db.set_key_attribute("missing", "team", "video").is_err());
+    }
+
+    #[test]
+    fn test_handle_is_read_only() {
+        let path = test_db_path("handle");
+        populate(&path, &[("a", 10.0, 1.0), ("a", 15.0, 2.0)]);
+        let handle = MetricsDbHandle::new(&path);
+        let samples = handle
+            .clone()
+            .query(|db| db.metrics_for_key("a", None))
+            .unwrap();
+        assert_eq!(samples.len(), 2);
+        assert!(handle
+            .query(|db| db.set_key_attribute("a", "team", "video"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let path = test_db_path("metadata");
+        populate(&path, &[("a", 10.0, 1.0)]);
+        let exporter = crate::SqliteExporter::builder()
+            .app_name("recorder")
+            .app_version("1.2.3")
+            .hostname("rig-01")
+            .build(&path)
+            .unwrap();
+        drop(exporter);
+        let metadata = MetricsDb::new(&path).unwrap().metadata().unwrap();
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["app_version"], "1.2.3");
+        assert_eq!(metadata["hostname"], "rig-01");
+    }
+
+    #[test]
+    fn test_kind_for_key() {
+        use metrics::{Key, Recorder};
+        let path = test_db_path("kind");
+        populate(&path, &[("legacy", 10.0, 1.0)]);
+        let exporter = crate::SqliteExporter::builder().build(&path).unwrap();
+        exporter
+            .register_counter(&Key::from_name("hits"))
+            .increment(1);
+        exporter.register_gauge(&Key::from_name("rate")).set(2.0);
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.kind_for_key("hits").unwrap(), MetricKind::Counter);
+        assert_eq!(db.kind_for_key("rate").unwrap(), MetricKind::Gauge);
+        assert!(matches!(
+            db.kind_for_key("legacy"),
+            Err(MetricsError::UnknownKind(_))
+        ));
+    }
+
+    #[test]
+    fn test_record_at() {
+        use metrics::Key;
+        let path = test_db_path("record-at");
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let exporter = crate::SqliteExporter::builder().build(&path).unwrap();
+        let key = Key::from_name("sensor");
+        exporter.record_histogram_at(&key, 2.0, at(20));
+        exporter.record_histogram_at(&key, 1.0, at(10));
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let samples: Vec<(f64, f64)> = db
+            .metrics_for_key("sensor", None)
+            .unwrap()
+            .iter()
+            .map(|m| (m.timestamp.0, m.value.0))
+            .collect();
+        assert_eq!(samples, vec![(10.0, 1.0), (20.0, 2.0)]);
+    }
+
+    #[test]
+    fn test_all_metrics_ordered() {
+        let path = test_db_path("all-ordered");
+        populate(
+            &path,
+            &[("b", 12.0, 3.0), ("a", 10.0, 1.0), ("b", 11.0, 2.0)],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let all = db
+            .all_metrics_ordered()
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            all,
+            vec![
+                ("a".to_string(), String::new(), Timestamp(10.0), Value(1.0)),
+                ("b".to_string(), String::new(), Timestamp(11.0), Value(2.0)),
+                ("b".to_string(), String::new(), Timestamp(12.0), Value(3.0))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_joined_metrics() {
+        let path = test_db_path("joined");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("b", 11.0, 2.0),
+                ("a", 100.0, 3.0),
+                ("b", 101.0, 4.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.joined_metrics(None).unwrap().len(), 4);
+        let session = db.sessions().unwrap()[1];
+        let joined = db.joined_metrics(Some(&session)).unwrap();
+        let rows: Vec<_> = joined.iter().map(|m| (m.key.as_str(), m.value)).collect();
+        assert_eq!(rows, vec![("a", Value(3.0)), ("b", Value(4.0))]);
+    }
+
+    #[test]
+    fn test_cdf_for_key() {
+        let path = test_db_path("cdf");
+        let samples: Vec<_> = (1..=10)
+            .map(|i| ("latency", i as f64, (11 - i) as f64))
+            .collect();
+        populate(&path, &samples);
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(
+            db.cdf_for_key("latency", 4, None).unwrap(),
+            vec![
+                (Value(3.0), 0.25),
+                (Value(5.0), 0.5),
+                (Value(8.0), 0.75),
+                (Value(10.0), 1.0)
+            ]
+        );
+        assert!(db.cdf_for_key("latency", 0, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_render_prometheus() {
+        use metrics::{Key, Label, Recorder, Unit};
+        let path = test_db_path("prometheus");
+        let exporter = crate::SqliteExporter::new(Duration::from_secs(60), None, &path).unwrap();
+        exporter.describe_counter(
+            "http.requests".into(),
+            Some(Unit::Count),
+            "Requests served".into(),
+        );
+        let requests = Key::from_parts("http.requests", vec![Label::new("host", "a")]);
+        exporter.register_counter(&requests).increment(3);
+        exporter
+            .register_gauge(&Key::from_name("temperature"))
+            .set(21.5);
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let rendered = db.render_prometheus().unwrap();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "# HELP http_requests Requests served (count)");
+        assert_eq!(lines[1], "# TYPE http_requests counter");
+        assert!(lines[2].starts_with("http_requests{host=\"a\"} 3 "));
+        assert_eq!(lines[3], "# TYPE temperature gauge");
+        assert!(lines[4].starts_with("temperature 21.5 "));
+    }
+
+    #[test]
+    fn test_quantile_for_key() {
+        let path = test_db_path("quantile");
+        let samples: Vec<_> = (1..=20)
+            .map(|i| ("latency", i as f64, (21 - i) as f64))
+            .collect();
+        populate(&path, &samples);
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.quantile_for_key("latency", None, 0.95).unwrap(), 19.0);
+        assert_eq!(db.quantile_for_key("latency", None, 0.5).unwrap(), 10.0);
+        assert_eq!(db.quantile_for_key("latency", None, 0.0).unwrap(), 1.0);
+        assert_eq!(db.quantile_for_key("latency", None, 1.0).unwrap(), 20.0);
+        assert!(matches!(
+            db.quantile_for_key("latency", None, 1.5),
+            Err(MetricsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_keep_last_sessions() {
+        let path = test_db_path("keep-sessions");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("a", 11.0, 1.0),
+                ("a", 100.0, 2.0),
+                ("a", 101.0, 2.0),
+                ("a", 200.0, 3.0),
+                ("a", 201.0, 3.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        for ts in [10.0, 200.0] {
+            diesel::insert_into(crate::schema::events::table)
+                .values(crate::models::NewEvent {
+                    timestamp: ts,
+                    name: "version".to_string(),
+                    value: ts.to_string(),
+                })
+                .execute(&mut db.db)
+                .unwrap();
+        }
+        assert_eq!(MetricsDb::keep_last_sessions(&mut db.db, 2).unwrap(), 2);
+        assert_eq!(MetricsDb::keep_last_sessions(&mut db.db, 2).unwrap(), 0);
+        let first = db.metrics_for_key("a", None).unwrap()[0].timestamp;
+        assert_eq!(first, Timestamp(100.0));
+        let events = db
+            .events_in_range(Timestamp(0.0), Timestamp(f64::MAX))
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].timestamp, Timestamp(200.0));
+    }
+
+    #[test]
+    fn test_metrics_for_key_in_range() {
+        let path = test_db_path("time-range");
+        populate(
+            &path,
+            &[("a", 10.0, 1.0), ("a", 20.0, 2.0), ("a", 30.0, 3.0)],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let values: Vec<f64> = db
+            .metrics_for_key_in_range("a", at(15), at(30))
+            .unwrap()
+            .iter()
+            .map(|m| m.value.0)
+            .collect();
+        assert_eq!(values, vec![2.0, 3.0]);
+        assert!(db
+            .metrics_for_key_in_range("a", at(31), at(40))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_archive_before() {
+        let path = test_db_path("archive");
+        populate(
+            &path,
+            &[
+                ("a", 0.0, 1.0),
+                ("a", 30.0, 3.0),
+                ("a", 60.0, 5.0),
+                ("a", 90.0, 7.0),
+                ("a", 130.0, 9.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(
+            MetricsDb::archive_before(&mut db.db, 150.0, 60.0).unwrap(),
+            4
+        );
+        let archived = db.archived_metrics_for_key("a").unwrap();
+        assert_eq!(archived.len(), 2);
+        assert_eq!(archived[1].timestamp, Timestamp(60.0));
+        assert_eq!(archived[1].sample_count, 2);
+        assert_eq!(archived[1].min_value, Value(5.0));
+        assert_eq!(archived[1].max_value, Value(7.0));
+        assert_eq!(archived[1].mean_value, Value(6.0));
+        let values = db
+            .values_for_key_in_range("a", Timestamp(30.0), Timestamp(200.0))
+            .unwrap();
+        assert_eq!(
+            values,
+            vec![
+                (Timestamp(60.0), Value(6.0)),
+                (Timestamp(130.0), Value(9.0))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_orphan_samples() {
+        let path = test_db_path("orphans");
+        populate(
+            &path,
+            &[("a", 10.0, 1.0), ("b", 11.0, 2.0), ("b", 12.0, 3.0)],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.find_orphan_samples().unwrap(), 0);
+        assert!(db
+            .with_connection(|db| sql_query("DELETE FROM metric_keys WHERE key = 'b'").execute(db))
+            .is_err());
+        db.with_connection(|db| {
+            sql_query("PRAGMA foreign_keys = OFF").execute(db)?;
+            sql_query("DELETE FROM metric_keys WHERE key = 'b'").execute(db)
+        })
+        .unwrap();
+        assert_eq!(db.find_orphan_samples().unwrap(), 2);
+        assert_eq!(db.delete_orphan_samples().unwrap(), 2);
+        assert_eq!(db.find_orphan_samples().unwrap(), 0);
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_scrub_non_finite() {
+        let path = test_db_path("scrub");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("a", 11.0, f64::INFINITY),
+                ("a", 12.0, f64::NEG_INFINITY),
+                ("a", 13.0, f64::MAX),
+                ("a", 14.0, f64::NAN),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.scrub_non_finite().unwrap(), 2);
+        let values: Vec<f64> = db
+            .metrics_for_key("a", None)
+            .unwrap()
+            .iter()
+            .map(|m| m.value.0)
+            .collect();
+        assert_eq!(values, vec![1.0, f64::MAX]);
+    }
+
+    #[cfg(feature = "export_csv")]
+    #[test]
+    fn test_export_range_to_csv() {
+        let path = test_db_path("export-range");
+        let csv_path = path.with_extension("csv");
+        populate(
+            &path,
+            &[("a", 10.0, 1.0), ("a", 20.0, 2.0), ("a", 30.0, 3.0)],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.export_range_to_csv(Timestamp(15.0), Timestamp(30.0), &csv_path)
+            .unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.contains(",20.0,a,,2.0"));
+        assert!(!csv.contains(",10.0,a,"));
+    }
+
+    #[cfg(feature = "export_csv")]
+    #[test]
+    fn test_export_to_csv_with_options() {
+        use crate::CsvExportOptions;
+        let path = test_db_path("export-options");
+        let csv_path = path.with_extension("csv");
+        populate(&path, &[("a", 10.0, 1.5)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let options = CsvExportOptions::default()
+            .delimiter(b';')
+            .quote_style(csv::QuoteStyle::NonNumeric)
+            .terminator(csv::Terminator::CRLF);
+        db.export_to_csv_with_options(&csv_path, &options).unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        assert_eq!(
+            csv,
+            "\"id\";\"timestamp\";\"key\";\"labels\";\"value\"\r\n1;10.0;\"a\";\"\";1.5\r\n"
+        );
+    }
+
+    #[cfg(feature = "export_jsonl")]
+    #[test]
+    fn test_export_to_jsonl() {
+        let path = test_db_path("export-jsonl");
+        let jsonl_path = path.with_extension("jsonl");
+        populate(&path, &[("b", 20.0, 2.5), ("a", 10.0, 1.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.export_to_jsonl(&jsonl_path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&jsonl_path).unwrap(),
+            "{\"t\":10.0,\"k\":\"a\",\"l\":\"\",\"v\":1.0}\n{\"t\":20.0,\"k\":\"b\",\"l\":\"\",\"v\":2.5}\n"
+        );
+    }
+
+    #[cfg(feature = "export_json")]
+    #[test]
+    fn test_export_to_json() {
+        let path = test_db_path("export-json");
+        let json_path = path.with_extension("json");
+        populate(&path, &[("b", 20.0, 2.5), ("a", 10.0, 1.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.export_to_json(&json_path).unwrap();
+        let exported: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(
+            exported,
+            serde_json::json!([
+                {"timestamp": 10.0, "key": "a", "labels": "", "value": 1.0},
+                {"timestamp": 20.0, "key": "b", "labels": "", "value": 2.5}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_key_transform() {
+        use crate::ValueTransform;
+        use metrics::Key;
+        let path = test_db_path("key-transform");
+        let exporter = crate::SqliteExporter::builder()
+            .key_transform(
+                "adc",
+                ValueTransform {
+                    scale: 0.5,
+                    offset: -1.0,
+                },
+            )
+            .build(&path)
+            .unwrap();
+        exporter.record_histogram_at(&Key::from_name("adc"), 10.0, SystemTime::now());
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(
+            db.metrics_for_key("adc", None).unwrap()[0].value,
+            Value(4.0)
+        );
+        let attributes = db.get_key_attributes("adc").unwrap();
+        assert_eq!(attributes["scale"], "0.5");
+        assert_eq!(attributes["offset"], "-1");
+    }
+
+    #[test]
+    fn test_health() {
+        let path = test_db_path("health");
+        populate(&path, &[("a", 10.0, 1.0), ("b", 20.0, 2.0)]);
+        let health = MetricsDb::new(&path).unwrap().health().unwrap();
+        assert!(health.schema_current);
+        assert!(!health.schema_version.is_empty());
+        assert_eq!(health.sample_count, 2);
+        assert_eq!(health.newest_timestamp, Some(20.0));
+        assert!(health.newest_age.unwrap() > Duration::from_secs(60 * 60 * 24 * 365));
+    }
+
+    #[test]
+    fn test_promote_on_shutdown() {
+        use metrics::Key;
+        let path = test_db_path("promote");
+        let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+        let exporter = crate::SqliteExporter::builder()
+            .promote_on_shutdown()
+            .build(&path)
+            .unwrap();
+        exporter.record_histogram_at(&Key::from_name("a"), 1.0, SystemTime::now());
+        assert!(temp_path.exists() && !path.exists());
+        exporter.shutdown().unwrap();
+        assert!(path.exists() && !temp_path.exists());
+        assert_eq!(
+            MetricsDb::new(&path).unwrap().available_keys().unwrap(),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn test_promote_with_wal_reader() {
+        let path = test_db_path("promote-wal");
+        let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+        let exporter = crate::SqliteExporter::builder()
+            .promote_on_shutdown()
+            .wal_mode(true)
+            .build(&path)
+            .unwrap();
+        exporter.record_value("a", 1.0, MetricKind::Gauge);
+        // an open reader keeps the temporary database's `-wal` & `-shm` past the worker closing
+        let mut reader = MetricsDb::new(&temp_path).unwrap();
+        reader.sample_counts().unwrap();
+        exporter.shutdown().unwrap();
+        assert!(!PathBuf::from(format!("{}-wal", temp_path.display())).exists());
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 1);
+        drop(reader);
+    }
+
+    #[test]
+    fn test_paged() {
+        let path = test_db_path("paged");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("a", 11.0, 2.0),
+                ("a", 11.5, 3.0),
+                ("a", 12.0, 4.0),
+                ("a", 13.0, 5.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let values = |page: Vec<Metric>| page.iter().map(|m| m.value.0).collect::<Vec<_>>();
+        let page = db.metrics_for_key_paged("a", 2, 2).unwrap();
+        assert_eq!(values(page), vec![3.0, 4.0]);
+        let first = db.metrics_for_key_after("a", None, 2).unwrap();
+        let second = db.metrics_for_key_after("a", first.last(), 2).unwrap();
+        assert_eq!(values(first), vec![1.0, 2.0]);
+        let third = db.metrics_for_key_after("a", second.last(), 2).unwrap();
+        assert_eq!(values(second), vec![3.0, 4.0]);
+        assert_eq!(values(third), vec![5.0]);
+    }
+
+    #[test]
+    fn test_duplicate_timestamps_kept() {
+        let path = test_db_path("duplicate-timestamp");
+        populate(&path, &[("a", 1.0, 1.0), ("a", 1.0, 2.0), ("b", 1.0, 3.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let samples = db.metrics_for_key("a", None).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(db.insert_metrics([("b", 1.0, 4.0)]).unwrap(), 1);
+        assert_eq!(db.metrics_for_key("b", None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_value_at() {
+        let path = test_db_path("value-at");
+        populate(
+            &path,
+            &[("g", 10.0, 1.0), ("g", 20.0, 3.0), ("g", 30.0, 3.0)],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.value_at("g", Timestamp(15.0)).unwrap(), Some(Value(2.0)));
+        assert_eq!(db.value_at("g", Timestamp(20.0)).unwrap(), Some(Value(3.0)));
+        assert_eq!(db.value_at("g", Timestamp(9.0)).unwrap(), None);
+        assert_eq!(db.value_at("g", Timestamp(31.0)).unwrap(), None);
+        MetricKey::set_kind("g", "", MetricKind::Counter, &mut db.db).unwrap();
+        assert_eq!(db.value_at("g", Timestamp(14.0)).unwrap(), Some(Value(1.0)));
+        assert_eq!(db.value_at("g", Timestamp(16.0)).unwrap(), Some(Value(3.0)));
+    }
+
+    #[test]
+    fn test_record_value() {
+        let path = test_db_path("record-value");
+        let exporter = crate::SqliteExporter::builder().build(&path).unwrap();
+        exporter.record_value("hits", 2.0, MetricKind::Counter);
+        exporter.record_value("hits", 3.0, MetricKind::Counter);
+        exporter.record_value("temp", 21.5, MetricKind::Gauge);
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let hits: Vec<f64> = db
+            .metrics_for_key("hits", None)
+            .unwrap()
+            .iter()
+            .map(|m| m.value.0)
+            .collect();
+        assert_eq!(hits, vec![2.0, 5.0]);
+        assert_eq!(
+            db.metrics_for_key("temp", None).unwrap()[0].value,
+            Value(21.5)
+        );
+    }
+
+    #[test]
+    fn test_fail_if_larger_than() {
+        let path = test_db_path("size-limit");
+        populate(&path, &[("a", 10.0, 1.0)]);
+        let result = crate::SqliteExporter::builder()
+            .fail_if_larger_than(1024)
+            .build(&path);
+        assert!(matches!(
+            result,
+            Err(MetricsError::DatabaseTooLarge { limit: 1024, .. })
+        ));
+        assert!(crate::SqliteExporter::builder()
+            .fail_if_larger_than(100 * 1024 * 1024)
+            .build(&path)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_insert_metrics() {
+        let path = test_db_path("insert-metrics");
+        populate(&path, &[("a", 10.0, 1.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let sessions = db.sessions().unwrap().len();
+        let inserted = db
+            .insert_metrics(vec![("a", 11.0, 2.0), ("b", 11.0, 5.0), ("b", 12.0, 6.0)])
+            .unwrap();
+        assert_eq!(inserted, 3);
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 2);
+        let b: Vec<f64> = db
+            .metrics_for_key("b", None)
+            .unwrap()
+            .iter()
+            .map(|m| m.value.0)
+            .collect();
+        assert_eq!(b, vec![5.0, 6.0]);
+        // cached sessions include inserted samples
+        db.insert_metrics([("a", 1000.0, 3.0)]).unwrap();
+        assert!(db.sessions().unwrap().len() > sessions);
+    }
+
+    #[test]
+    fn test_counter_increments() {
+        let path = test_db_path("counter-increments");
+        populate(
+            &path,
+            &[
+                ("c", 1.0, 2.0),
+                ("c", 2.0, 5.0),
+                ("c", 3.0, 5.0),
+                ("c", 4.0, 3.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(
+            db.counter_increments_for_key("c", None).unwrap(),
+            [(2.0, 3.0), (3.0, 0.0), (4.0, 3.0)].map(|(t, v)| (Timestamp(t), Value(v)))
+        );
+    }
+
+    #[test]
+    fn test_session_window() {
+        let path = test_db_path("session-window");
+        populate(
+            &path,
+            &[
+                ("a", 0.0, 1.0),
+                ("a", 10.0, 1.0),
+                ("a", 100.0, 1.0),
+                ("a", 110.0, 1.0),
+            ],
+        );
+        assert_eq!(MetricsDb::new(&path).unwrap().sessions().unwrap().len(), 2);
+        let sessions = MetricsDb::new_with_window(&path, Timestamp(50.0))
+            .unwrap()
+            .sessions()
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].start_time, 100.0);
+        assert!(MetricsDb::new_with_window(&path, Timestamp(200.0))
+            .unwrap()
+            .sessions()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_lazy_sessions() {
+        let path = test_db_path("lazy-sessions");
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert!(db.sessions().unwrap().is_empty());
+        populate(&path, &[("a", 10.0, 1.0), ("a", 11.0, 1.0)]);
+        // cached until recalculation is needed
+        assert!(db.sessions().unwrap().is_empty());
+        db.set_session_thresholds(SessionThresholds::default())
+            .unwrap();
+        assert_eq!(db.sessions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_truncate_samples() {
+        let path = test_db_path("truncate");
+        populate(&path, &[("a", 10.0, 1.0), ("b", 11.0, 2.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.truncate_samples().unwrap();
+        assert_eq!(db.available_keys().unwrap(), vec!["a", "b"]);
+        assert!(db.metrics_for_key("a", None).unwrap().is_empty());
+        assert!(db.sessions().unwrap().is_empty());
+        db.clear_all().unwrap();
+        assert!(db.available_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_vacuum_into() {
+        let path = test_db_path("vacuum-into-src");
+        let dest = test_db_path("vacuum-into-dest");
+        populate(&path, &[("a", 10.0, 1.0), ("a", 11.0, 2.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.vacuum_into(&dest).unwrap();
+        assert!(db.vacuum_into(&dest).is_err());
+        let mut copy = MetricsDb::new(&dest).unwrap();
+        assert_eq!(copy.metrics_for_key("a", None).unwrap().len(), 2);
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_ratio_of_keys() {
+        let path = test_db_path("ratio");
+        populate(
+            &path,
+            &[
+                ("errors", 10.0, 1.0),
+                ("requests", 10.1, 10.0),
+                ("errors", 20.0, 4.0),
+                ("requests", 19.0, 0.0),
+                ("errors", 30.0, 6.0),
+                ("requests", 29.8, 20.0),
+                ("requests", 35.0, 40.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let ratios = db.ratio_of_keys("errors", "requests", None).unwrap();
+        assert_eq!(
+            ratios,
+            vec![(Timestamp(10.0), Value(0.1)), (Timestamp(30.0), Value(0.3))]
+        );
+    }
+
+    #[test]
+    fn test_session_dominant_keys() {
+        let path = test_db_path("dominant-keys");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("b", 11.0, 1.0),
+                ("b", 12.0, 1.0),
+                ("a", 100.0, 1.0),
+                ("a", 101.0, 1.0),
+                ("b", 102.0, 1.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let labels: Vec<String> = db
+            .session_dominant_keys()
+            .unwrap()
+            .into_iter()
+            .map(|(_, (key, _))| key)
+            .collect();
+        assert_eq!(labels, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn test_aligned_series() {
+        let path = test_db_path("aligned-series");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("a", 10.5, 3.0),
+                ("b", 11.2, 5.0),
+                ("a", 12.9, 4.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let (axis, values) = db
+            .aligned_series(&["a", "b"], Duration::from_secs(1), None)
+            .unwrap();
+        assert_eq!(
+            axis,
+            vec![Timestamp(10.0), Timestamp(11.0), Timestamp(12.0)]
+        );
+        assert_eq!(
+            values,
+            vec![
+                vec![Some(Value(2.0)), None, Some(Value(4.0))],
+                vec![None, Some(Value(5.0)), None]
+            ]
+        );
+        assert!(db.aligned_series(&["a"], Duration::ZERO, None).is_err());
+    }
+
+    #[test]
+    fn test_session_tick_counts() {
+        let path = test_db_path("tick-counts");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("b", 10.0, 1.0),
+                ("a", 11.0, 1.0),
+                ("a", 100.0, 1.0),
+                ("b", 101.0, 1.0),
+                ("a", 102.0, 1.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let ticks: Vec<u64> = db
+            .session_tick_counts()
+            .unwrap()
+            .into_iter()
+            .map(|(_, ticks)| ticks)
+            .collect();
+        assert_eq!(ticks, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_custom_functions() {
+        use diesel::sql_types::Double;
+        diesel::define_sql_function!(fn celsius(fahrenheit: Double) -> Double);
+        #[derive(QueryableByName)]
+        struct Row {
+            #[diesel(sql_type = Double)]
+            value: f64,
+        }
+        let path = test_db_path("functions");
+        populate(&path, &[("temp", 10.0, 212.0)]);
+        let mut db = MetricsDb::new_with_functions(&path, |db| {
+            celsius_utils::register_impl(db, |fahrenheit: f64| (fahrenheit - 32.0) * 5.0 / 9.0)
+        })
+        .unwrap();
+        let rows = db
+            .with_connection(|db| {
+                sql_query("SELECT celsius(value) AS value FROM metrics").load::<Row>(db)
+            })
+            .unwrap();
+        assert_eq!(rows[0].value, 100.0);
+    }
+
+    #[test]
+    fn test_gaps_for_key() {
+        let path = test_db_path("gaps");
+        let samples: Vec<(&str, f64, f64)> = [10.0, 11.0, 12.0, 20.0, 21.0, 25.5]
+            .iter()
+            .map(|t| ("a", *t, 1.0))
+            .collect();
+        populate(&path, &samples);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let gaps = db.gaps_for_key("a", Duration::from_secs(2), None).unwrap();
+        assert_eq!(
+            gaps,
+            vec![
+                (Timestamp(12.0), Timestamp(20.0)),
+                (Timestamp(21.0), Timestamp(25.5))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_annotations() {
+        let path = test_db_path("annotations");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("a", 11.0, 1.0),
+                ("a", 100.0, 1.0),
+                ("a", 101.0, 1.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let sessions = db.sessions().unwrap();
+        assert_eq!(sessions.len(), 2);
+        db.annotate_session(&sessions[0], "baseline").unwrap();
+        db.annotate_session(&sessions[0], "cold cache").unwrap();
+        assert_eq!(
+            db.session_annotations(&sessions[0]).unwrap(),
+            vec!["baseline", "cold cache"]
+        );
+        assert!(db.session_annotations(&sessions[1]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_key_creation_timeline() {
+        let path = test_db_path("key-creation");
+        let day = 86_400.0;
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("b", 20.0, 1.0),
+                ("a", day + 10.0, 1.0),
+                ("c", 3.0 * day + 5.0, 1.0),
+                ("d", 3.0 * day + 6.0, 1.0),
+                ("e", 3.0 * day + 7.0, 1.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(
+            db.key_creation_timeline().unwrap(),
+            vec![(Timestamp(0.0), 2), (Timestamp(3.0 * day), 3)]
+        );
+    }
+
+    #[test]
+    fn test_metrics_for_key_converted() {
+        let path = test_db_path("converted");
+        populate(&path, &[("mem", 10.0, 2097152.0), ("fps", 10.0, 60.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        MetricKey::create_or_update("mem", Some(Unit::Bytes), None, &mut db.db).unwrap();
+        let converted = db
+            .metrics_for_key_converted("mem", Unit::Mebibytes, None)
+            .unwrap();
+        assert_eq!(converted[0].value, Value(2.0));
+        assert!(matches!(
+            db.metrics_for_key_converted("mem", Unit::Seconds, None),
+            Err(MetricsError::IncompatibleUnits { .. })
+        ));
+        assert!(matches!(
+            db.metrics_for_key_converted("fps", Unit::Count, None),
+            Err(MetricsError::IncompatibleUnits { .. })
+        ));
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let path = test_db_path("delete-range");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("b", 11.0, 1.0),
+                ("a", 12.0, 1.0),
+                ("b", 13.0, 1.0),
+                ("a", 14.0, 1.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let (start, end) = (Timestamp(11.0), Timestamp(13.0));
+        assert_eq!(db.delete_range_for_key("a", start, end).unwrap(), 1);
+        assert_eq!(db.delete_range(start, end).unwrap(), 2);
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 2);
+        assert!(db.metrics_for_key("b", None).unwrap().is_empty());
+        assert_eq!(db.sessions().unwrap()[0].start_time, 10.0);
+    }
+
+    #[test]
+    fn test_open_snapshot() {
+        let path = test_db_path("snapshot");
+        populate(&path, &[("a", 10.0, 1.0)]);
+        MetricsDb::new(&path)
+            .unwrap()
+            .with_connection(|db| sql_query("PRAGMA journal_mode = WAL").execute(db))
+            .unwrap();
+        let mut snapshot = MetricsDb::open_snapshot(&path).unwrap();
+        assert_eq!(snapshot.metrics_for_key("a", None).unwrap().len(), 1);
+        populate(&path, &[("a", 11.0, 2.0)]);
+        assert_eq!(snapshot.metrics_for_key("a", None).unwrap().len(), 1);
+        snapshot.refresh_snapshot().unwrap();
+        assert_eq!(snapshot.metrics_for_key("a", None).unwrap().len(), 2);
+        assert!(snapshot
+            .delete_range(Timestamp(0.0), Timestamp(20.0))
+            .is_err());
+        // transactional writes fail as read-only, leaving the snapshot usable
+        let error = snapshot.delete_key("a").unwrap_err();
+        assert!(error.to_string().contains("readonly"), "{}", error);
+        assert_eq!(snapshot.diff(&path, "a").unwrap().len(), 2);
+        snapshot.refresh_snapshot().unwrap();
+        assert_eq!(snapshot.metrics_for_key("a", None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_growth_rate() {
+        let path = test_db_path("growth-rate");
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+        let samples: Vec<(&str, f64, f64)> = (0..10)
+            .map(|i| ("a", now - 100.0 - i as f64, 1.0))
+            .collect();
+        populate(&path, &samples);
+        populate(&path, &[("a", now - 5.0, 1.0), ("a", now - 2.0, 1.0)]);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let bytes_per_sample = crate::used_bytes(&mut db.db).unwrap() as f64 / 12.0;
+        let rate = db.growth_rate(Duration::from_secs(10)).unwrap();
+        assert!((rate - 0.2 * bytes_per_sample).abs() < 1e-9);
+        assert!(db.growth_rate(Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_key_normalizer() {
+        let path = test_db_path("key-normalizer");
+        let normalize = |key: &str| key.replace('.', "_").to_lowercase();
+        let exporter = crate::SqliteExporter::builder()
+            .key_normalizer(normalize)
+            .build(&path)
+            .unwrap();
+        exporter.record_value("HTTP.requests", 1.0, MetricKind::Counter);
+        exporter.record_value("http_requests", 1.0, MetricKind::Counter);
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.available_keys().unwrap(), vec!["http_requests"]);
+        db.set_key_normalizer(normalize);
+        let values: Vec<f64> = db
+            .metrics_for_key("http.requests", None)
+            .unwrap()
+            .iter()
+            .map(|m| m.value.0)
+            .collect();
+        assert_eq!(values, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_run_housekeeping() {
+        let path = test_db_path("run-housekeeping");
+        let samples: Vec<_> = (0..8).map(|i| ("a", i as f64, i as f64)).collect();
+        populate(&path, &samples);
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.run_housekeeping(None, Some(4), false).unwrap();
+        let values: Vec<_> = db
+            .metrics_for_key("a", None)
+            .unwrap()
+            .iter()
+            .map(|m| m.value.0)
+            .collect();
+        assert_eq!(values, vec![5.0, 6.0, 7.0]);
+        // retention reaching back before the epoch keeps everything
+        db.run_housekeeping(Some(Duration::MAX), None, false)
+            .unwrap();
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 3);
+        db.run_housekeeping(Some(Duration::from_secs(60)), None, true)
+            .unwrap();
+        assert!(db.metrics_for_key("a", None).unwrap().is_empty());
+        assert!(db.sessions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_record_limit_boundary() {
+        let path = test_db_path("record-limit-boundary");
+        let samples: Vec<_> = (0..4).map(|i| ("a", i as f64, i as f64)).collect();
+        populate(&path, &samples);
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.run_housekeeping(None, Some(4), false).unwrap();
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 4);
+        populate(&path, &[("a", 4.0, 4.0)]);
+        // one over the limit removes it plus 25% of the limit, oldest first
+        db.run_housekeeping(None, Some(4), false).unwrap();
+        let first = db.metrics_for_key("a", None).unwrap()[0].value.0;
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 3);
+        assert_eq!(first, 2.0);
+    }
+
+    #[test]
+    fn test_delete_key() {
+        let path = test_db_path("delete-key");
+        populate(
+            &path,
+            &[("a", 10.0, 1.0), ("a", 11.0, 2.0), ("b", 10.0, 5.0)],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.delete_key("a").unwrap(), 2);
+        assert_eq!(db.available_keys().unwrap(), vec!["b"]);
+        assert_eq!(db.metrics_for_key("b", None).unwrap().len(), 1);
+        assert!(matches!(
+            db.delete_key("a"),
+            Err(MetricsError::KeyNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_sample_counts() {
+        let path = test_db_path("sample-counts");
+        populate(
+            &path,
+            &[("a", 10.0, 1.0), ("a", 11.0, 2.0), ("b", 10.0, 5.0)],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        db.delete_range_for_key("b", Timestamp(0.0), Timestamp(20.0))
+            .unwrap();
+        let counts = db.sample_counts().unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["a"], 2);
+        assert_eq!(counts["b"], 0);
+    }
+
+    #[test]
+    fn test_latest_values_for() {
+        let path = test_db_path("latest-values");
+        populate(
+            &path,
+            &[
+                ("a", 10.0, 1.0),
+                ("a", 12.0, 3.0),
+                ("a", 11.0, 2.0),
+                ("b", 10.0, 5.0),
+                ("c", 20.0, 7.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let latest = db.latest_values_for(&["a", "c", "missing"]).unwrap();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest["a"], (Timestamp(12.0), Value(3.0)));
+        assert_eq!(latest["c"], (Timestamp(20.0), Value(7.0)));
+        assert!(db.latest_values_for(&[]).unwrap().is_empty());
+        // more keys than a query can bind are looked up in chunks
+        let many: Vec<String> = (0..2500).map(|i| format!("missing-{}", i)).collect();
+        let mut names: Vec<&str> = many.iter().map(String::as_str).collect();
+        names.push("b");
+        let latest = db.latest_values_for(&names).unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest["b"], (Timestamp(10.0), Value(5.0)));
+    }
+
+    #[test]
+    fn test_record_call_counts() {
+        use crate::{Deadband, MetricKind};
+        let path = test_db_path("call-counts");
+        let exporter = crate::SqliteExporter::builder()
+            .record_call_counts(true)
+            .gauge_deadband(Deadband {
+                epsilon: 10.0,
+                max_interval: None,
+            })
+            .build(&path)
+            .unwrap();
+        for _ in 0..3 {
+            exporter.record_value("g", 5.0, MetricKind::Gauge);
+        }
+        exporter.record_value("g", 50.0, MetricKind::Gauge);
+        drop(exporter);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let counts: Vec<(f64, Option<u64>)> = db
+            .metrics_with_call_counts_for_key("g", None)
+            .unwrap()
+            .into_iter()
+            .map(|(_, v, count)| (v.0, count))
+            .collect();
+        assert_eq!(counts, vec![(5.0, Some(1)), (50.0, Some(3))]);
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = test_db_path("diff-before");
+        let after = test_db_path("diff-after");
+        populate(&before, &[("fps", 10.0, 30.0), ("fps", 11.0, 40.0)]);
+        populate(&after, &[("fps", 500.0, 50.0), ("fps", 501.1, 60.0)]);
+        // labeled series of the same key aren't compared
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(&*after).unwrap());
+        state
+            .queue_metric(Duration::from_secs_f64(499.0), "fps", "gpu=1", 0.0)
+            .unwrap();
+        state.flush().unwrap();
+        drop(state);
+        let mut db = MetricsDb::new(&before).unwrap();
+        let diff = db.diff(&after, "fps").unwrap();
+        let differences: Vec<(f64, f64)> = diff.iter().map(|p| (p.offset, p.difference)).collect();
+        assert_eq!(differences, vec![(0.0, 20.0), (1.0, 20.0)]);
+        // detached again so it can be attached on the next call
+        assert_eq!(db.diff(&after, "fps").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_compact_key() {
+        let path = test_db_path("compact-key");
+        let samples: Vec<(&str, f64, f64)> = [1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 3.0, 3.0, 3.0]
+            .iter()
+            .enumerate()
+            .map(|(i, v)| ("g", i as f64, *v))
+            .collect();
+        populate(&path, &samples);
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.compact_key("g").unwrap(), 3);
+        let remaining: Vec<(f64, f64)> = db
+            .metrics_for_key("g", None)
+            .unwrap()
+            .iter()
+            .map(|m| (m.timestamp.0, m.value.0))
+            .collect();
+        assert_eq!(
+            remaining,
+            vec![
+                (0.0, 1.0),
+                (3.0, 1.0),
+                (4.0, 2.0),
+                (5.0, 2.0),
+                (6.0, 3.0),
+                (8.0, 3.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_latest_session() {
+        let path = test_db_path("latest-session");
+        let mut samples: Vec<(&str, f64, f64)> = (0..2500).map(|i| ("a", i as f64, 1.0)).collect();
+        samples.extend((0..1500).map(|i| ("a", 5000.0 + i as f64, 1.0)));
+        populate(&path, &samples);
+        let mut db = MetricsDb::new(&path).unwrap();
+        let latest = db.latest_session().unwrap().unwrap();
+        assert_eq!(latest.start_time, 5000.0);
+        assert_eq!(latest.end_time, 6499.0);
+        assert_eq!(latest.preceded_by, Some(SessionGap::Pause));
+        let last = *db.sessions().unwrap().last().unwrap();
+        assert_eq!((last.start_time, last.end_time), (5000.0, 6499.0));
+    }
+
+    #[test]
+    fn test_stuck_keys() {
+        let path = test_db_path("stuck-keys");
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+        populate(
+            &path,
+            &[
+                ("stuck", now - 3.0, 4.0),
+                ("stuck", now - 2.0, 4.0),
+                ("stuck", now - 1.0, 4.0),
+                ("moving", now - 2.0, 1.0),
+                ("moving", now - 1.0, 2.0),
+                ("single", now - 1.0, 1.0),
+                ("old", now - 1000.0, 1.0),
+                ("old", now - 999.0, 1.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(
+            db.stuck_keys(Duration::from_secs(60)).unwrap(),
+            vec![("stuck".to_string(), String::new())]
+        );
+    }
+
+    #[cfg(feature = "import_csv")]
+    #[test]
+    fn test_import_on_conflict() {
+        let path = test_db_path("import-conflict");
+        let csv_path = path.with_extension("csv");
+        std::fs::write(
+            &csv_path,
+            "id,timestamp,key,value\n1,1.0,a,1.0\n2,2.0,a,2.0\n3,3.0,a,not-a-number\n",
+        )
+        .unwrap();
+        let stats = MetricsDb::import_from_csv(&csv_path, &path).unwrap();
+        assert_eq!((stats.read, stats.inserted, stats.skipped), (3, 2, 1));
+        let stats =
+            MetricsDb::import_from_csv_with_conflict(&csv_path, &path, OnConflict::Skip).unwrap();
+        assert_eq!((stats.read, stats.inserted, stats.skipped), (3, 0, 3));
+        let stats = MetricsDb::import_from_csv_with_conflict(&csv_path, &path, OnConflict::Replace)
+            .unwrap();
+        assert_eq!((stats.read, stats.inserted, stats.skipped), (3, 2, 1));
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 2);
+        drop(db);
+        // default import keeps existing samples of the same timestamps
+        let stats = MetricsDb::import_from_csv(&csv_path, &path).unwrap();
+        assert_eq!((stats.read, stats.inserted, stats.skipped), (3, 2, 1));
+        let mut db = MetricsDb::new(&path).unwrap();
+        assert_eq!(db.metrics_for_key("a", None).unwrap().len(), 4);
+    }
+
+    #[cfg(all(feature = "export_csv", feature = "import_csv"))]
+    #[test]
+    fn test_csv_round_trip_keeps_labels() {
+        let path = test_db_path("csv-round-trip");
+        let csv_path = path.with_extension("csv");
+        let imported = test_db_path("csv-round-trip-imported");
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(&*path).unwrap());
+        for (labels, value) in [("", 1.0), ("host=a", 2.0), ("host=b", 3.0)] {
+            state
+                .queue_metric(Duration::from_secs(10), "requests", labels, value)
+                .unwrap();
+        }
+        state.flush().unwrap();
+        drop(state);
+        MetricsDb::new(&path)
+            .unwrap()
+            .export_to_csv(&csv_path)
+            .unwrap();
+        let stats = MetricsDb::import_from_csv(&csv_path, &imported).unwrap();
+        assert_eq!((stats.read, stats.inserted, stats.skipped), (3, 3, 0));
+        let mut db = MetricsDb::new(&imported).unwrap();
+        let rows: Vec<_> = db
+            .joined_metrics(None)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.labels, m.value))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (String::new(), Value(1.0)),
+                ("host=a".to_string(), Value(2.0)),
+                ("host=b".to_string(), Value(3.0))
+            ]
+        );
+    }
+
+    #[cfg(feature = "import_csv")]
+    #[test]
+    fn test_import_stats_across_flushes() {
+        let path = test_db_path("import-stats");
+        let csv_path = path.with_extension("csv");
+        let mut csv = "id,timestamp,key,value\n".to_string();
+        for i in 0..250 {
+            csv.push_str(&format!("{},{}.0,a,1.0\n", i, i % 230));
+        }
+        std::fs::write(&csv_path, csv).unwrap();
+        // repeated timestamps are counted by what flushes actually wrote, not what was queued
+        let stats =
+            MetricsDb::import_from_csv_with_conflict(&csv_path, &path, OnConflict::Skip).unwrap();
+        assert_eq!((stats.read, stats.inserted, stats.skipped), (250, 230, 20));
+        let stats =
+            MetricsDb::import_from_csv_with_conflict(&csv_path, &path, OnConflict::Skip).unwrap();
+        assert_eq!((stats.read, stats.inserted, stats.skipped), (250, 0, 250));
+    }
+
+    #[test]
+    fn test_cadence_histogram() {
+        let path = test_db_path("cadence-histogram");
+        populate(
+            &path,
+            &[
+                ("a", 0.0, 1.0),
+                ("a", 0.5, 1.0),
+                ("a", 2.5, 1.0),
+                ("a", 3.0, 1.0),
+                ("a", 10.0, 1.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let buckets = [Duration::from_secs(1), Duration::from_secs(5)];
+        assert_eq!(
+            db.cadence_histogram_for_key("a", &buckets, None).unwrap(),
+            vec![2, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_windowed_aggregate() {
+        let path = test_db_path("windowed-aggregate");
+        let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs_f64();
+        populate(
+            &path,
+            &[
+                ("a", now - 1000.0, 100.0),
+                ("a", now - 20.0, 1.0),
+                ("a", now - 10.0, 3.0),
+            ],
+        );
+        let mut db = MetricsDb::new(&path).unwrap();
+        let window = Duration::from_secs(60);
+        assert_eq!(
+            db.windowed_aggregate("a", window, BucketAgg::Mean).unwrap(),
+            Some(2.0)
+        );
+        assert_eq!(
+            db.windowed_aggregate("a", window, BucketAgg::Count)
+                .unwrap(),
+            Some(2.0)
+        );
+        assert_eq!(
+            db.windowed_aggregate("a", Duration::from_secs(1), BucketAgg::Max)
+                .unwrap(),
+            None
+        );
+    }
 }
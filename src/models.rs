@@ -1,20 +1,46 @@
 //! Diesel models of metrics sqlite storage
-use crate::schema::{metric_keys, metrics};
+#[cfg(feature = "histogram_summary")]
+use crate::schema::histogram_summaries;
+use crate::schema::{
+    db_metadata, events, histogram_buckets, metric_key_attributes, metric_keys, metrics,
+    metrics_archive,
+};
 use crate::{MetricsError, Result};
 use ::metrics::Unit;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
 use diesel::prelude::*;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::{Double, Text};
+use diesel::sqlite::Sqlite;
 use std::borrow::Cow;
+use std::collections::HashMap;
 
 /// A new metric measurement for storing into sqlite database
 #[derive(Insertable, Debug)]
-#[diesel(table_name = metrics)]
+#[diesel(table_name = metrics, treat_none_as_default_value = false)]
 pub struct NewMetric {
     /// Timestamp of sample
-    pub timestamp: f64,
+    pub timestamp: Timestamp,
     /// Key/name of sample
     pub metric_key_id: i64,
     /// Value of sample
-    pub value: f64,
+    pub value: Value,
+    /// Number of times key was recorded since its previous stored sample, if call counts are
+    /// recorded
+    pub(crate) call_count: Option<i64>,
+}
+impl NewMetric {
+    /// Creates new sample of given key without a call count
+    pub fn new(timestamp: Timestamp, metric_key_id: i64, value: Value) -> Self {
+        NewMetric {
+            timestamp,
+            metric_key_id,
+            value,
+            call_count: None,
+        }
+    }
 }
 
 /// New metric key entry
@@ -27,6 +53,111 @@ pub struct NewMetricKey<'a> {
     pub unit: Cow<'a, str>,
     /// Description of metric key if any
     pub description: Cow<'a, str>,
+    /// Labels as from `labels_string()`, empty if unlabeled
+    pub(crate) labels: Cow<'a, str>,
+}
+
+/// New or updated metric key attribute entry
+#[derive(Insertable, Debug)]
+#[diesel(table_name = metric_key_attributes)]
+struct NewMetricKeyAttribute<'a> {
+    metric_key_id: i64,
+    name: &'a str,
+    value: &'a str,
+}
+
+/// Type a metric was registered as, deciding how it's best presented (e.g. counters as rates)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum MetricKind {
+    /// Cumulative counter
+    Counter,
+    /// Gauge
+    Gauge,
+    /// Histogram observations
+    Histogram,
+}
+impl MetricKind {
+    /// Name stored in database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+impl ToSql<Text, Sqlite> for MetricKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+        out.set_value(self.as_str());
+        Ok(IsNull::No)
+    }
+}
+impl FromSql<Text, Sqlite> for MetricKind {
+    fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        let name = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        match name.as_str() {
+            "counter" => Ok(MetricKind::Counter),
+            "gauge" => Ok(MetricKind::Gauge),
+            "histogram" => Ok(MetricKind::Histogram),
+            other => Err(format!("Unknown metric kind: {}", other).into()),
+        }
+    }
+}
+
+/// Timestamp of a sample, in seconds since UNIX epoch
+#[derive(Debug, Default, Copy, Clone, PartialEq, PartialOrd, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Double)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
+pub struct Timestamp(pub f64);
+impl From<f64> for Timestamp {
+    fn from(seconds: f64) -> Self {
+        Timestamp(seconds)
+    }
+}
+impl From<Timestamp> for f64 {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.0
+    }
+}
+impl ToSql<Double, Sqlite> for Timestamp {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+        out.set_value(self.0);
+        Ok(IsNull::No)
+    }
+}
+impl FromSql<Double, Sqlite> for Timestamp {
+    fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        <f64 as FromSql<Double, Sqlite>>::from_sql(bytes).map(Timestamp)
+    }
+}
+
+/// Value of a sample
+#[derive(Debug, Default, Copy, Clone, PartialEq, PartialOrd, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Double)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(transparent))]
+pub struct Value(pub f64);
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value(value)
+    }
+}
+impl From<Value> for f64 {
+    fn from(value: Value) -> Self {
+        value.0
+    }
+}
+impl ToSql<Double, Sqlite> for Value {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+        out.set_value(self.0);
+        Ok(IsNull::No)
+    }
+}
+impl FromSql<Double, Sqlite> for Value {
+    fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        <f64 as FromSql<Double, Sqlite>>::from_sql(bytes).map(Value)
+    }
 }
 
 /// Metric key
@@ -41,8 +172,23 @@ pub struct MetricKey<'a> {
     pub unit: Cow<'a, str>,
     /// Description of metric key if any
     pub description: Cow<'a, str>,
+    /// Type metric was registered as, None if recorded before types were stored
+    pub(crate) kind: Option<MetricKind>,
+    /// Labels of the series as from `labels_string()`, empty if unlabeled, see
+    /// `MetricsDb::label_sets_for_key()`
+    pub(crate) labels: Cow<'a, str>,
 }
 impl<'a> MetricKey<'a> {
+    /// Returns labels of the series as sorted `(name, value)` pairs, empty if unlabeled
+    pub fn labels(&self) -> Vec<(String, String)> {
+        parse_labels(&self.labels)
+    }
+    /// Returns type metric was registered as, None if recorded before types were stored
+    pub fn kind(&self) -> Option<MetricKind> {
+        self.kind
+    }
+    /// Describes every series of given key, creating its unlabeled entry if needed, which series
+    /// labeled later take their details from
     pub(crate) fn create_or_update(
         key_name: &str,
         unit: Option<Unit>,
@@ -54,60 +200,377 @@ impl<'a> MetricKey<'a> {
             .map(|u| Cow::Owned(u.as_str().to_string()))
             .unwrap_or(Cow::Borrowed(""));
         let description = description.map(Cow::Borrowed).unwrap_or(Cow::Borrowed(""));
-        Self::update(key.id, unit_value, description, db)?;
+        Self::update(key_name, unit_value, description, db)?;
         Ok(key)
     }
     fn update(
-        id_value: i64,
+        key_name: &str,
         unit_value: Cow<'a, str>,
         description_value: Cow<'a, str>,
         db: &mut SqliteConnection,
     ) -> Result<()> {
         use crate::schema::metric_keys::dsl::*;
-        diesel::update(metric_keys.filter(id.eq(id_value)))
+        diesel::update(metric_keys.filter(key.eq(key_name)))
             .set((unit.eq(unit_value), description.eq(description_value)))
             .execute(db)?;
         Ok(())
     }
+    /// Stores type of given metric key & labels, creating key entry if needed
+    pub(crate) fn set_kind(
+        key_name: &str,
+        key_labels: &str,
+        kind_value: MetricKind,
+        db: &mut SqliteConnection,
+    ) -> Result<()> {
+        use crate::schema::metric_keys::dsl::*;
+        let metric_key = Self::key_by_labels(key_name, key_labels, db)?;
+        // keys are registered again every run, only write when the type changed
+        if metric_key.kind != Some(kind_value) {
+            diesel::update(metric_keys.filter(id.eq(metric_key.id)))
+                .set(kind.eq(kind_value))
+                .execute(db)?;
+        }
+        Ok(())
+    }
+    /// Sets (inserting or replacing) a user defined attribute on given metric key
+    pub(crate) fn set_attribute(
+        key_id: i64,
+        attribute_name: &str,
+        attribute_value: &str,
+        db: &mut SqliteConnection,
+    ) -> Result<()> {
+        use crate::schema::metric_key_attributes::dsl::*;
+        let new_attribute = NewMetricKeyAttribute {
+            metric_key_id: key_id,
+            name: attribute_name,
+            value: attribute_value,
+        };
+        diesel::insert_into(metric_key_attributes)
+            .values(&new_attribute)
+            .on_conflict((metric_key_id, name))
+            .do_update()
+            .set(value.eq(attribute_value))
+            .execute(db)?;
+        Ok(())
+    }
+    /// Returns all user defined attributes of given metric key
+    pub(crate) fn attributes(
+        key_id: i64,
+        db: &mut SqliteConnection,
+    ) -> Result<HashMap<String, String>> {
+        use crate::schema::metric_key_attributes::dsl::*;
+        let attributes = metric_key_attributes
+            .filter(metric_key_id.eq(key_id))
+            .select((name, value))
+            .load::<(String, String)>(db)?;
+        Ok(attributes.into_iter().collect())
+    }
+    /// Returns whether a key entry exists for given labels, without creating it
+    pub(crate) fn exists(
+        key_name: &str,
+        key_labels: &str,
+        db: &mut SqliteConnection,
+    ) -> Result<bool> {
+        use crate::schema::metric_keys::dsl::*;
+        use diesel::dsl::{exists, select};
+        let query = metric_keys.filter(key.eq(key_name).and(labels.eq(key_labels)));
+        Ok(select(exists(query)).get_result(db)?)
+    }
+    /// Returns number of key entries
+    pub(crate) fn count(db: &mut SqliteConnection) -> Result<usize> {
+        use crate::schema::metric_keys::dsl::*;
+        Ok(metric_keys.count().get_result::<i64>(db)? as usize)
+    }
     pub(crate) fn key_by_name(key_name: &str, db: &mut SqliteConnection) -> Result<MetricKey<'a>> {
+        Self::key_by_labels(key_name, "", db)
+    }
+    /// Returns entry of given key & labels, creating it if needed
+    pub(crate) fn key_by_labels(
+        key_name: &str,
+        key_labels: &str,
+        db: &mut SqliteConnection,
+    ) -> Result<MetricKey<'a>> {
         use crate::schema::metric_keys::dsl::metric_keys;
-        match Self::key_by_name_inner(key_name, db) {
+        match Self::key_by_labels_inner(key_name, key_labels, db) {
             Ok(key) => Ok(key),
             Err(MetricsError::KeyNotFound(_)) => {
-                // not stored yet so create an entry
+                // not stored yet so create an entry, labeled series described like their key
+                let (unit, description) = match key_labels.is_empty() {
+                    true => (Cow::Borrowed(""), Cow::Borrowed("")),
+                    false => match Self::key_by_labels_inner(key_name, "", db) {
+                        Ok(base) => (base.unit, base.description),
+                        Err(MetricsError::KeyNotFound(_)) => (Cow::Borrowed(""), Cow::Borrowed("")),
+                        Err(e) => return Err(e),
+                    },
+                };
                 let new_key = NewMetricKey {
                     key: Cow::Borrowed(key_name),
-                    unit: Cow::Borrowed(""),
-                    description: Cow::Borrowed(""),
+                    unit,
+                    description,
+                    labels: Cow::Borrowed(key_labels),
                 };
                 new_key.insert_into(metric_keys).execute(db)?;
                 // fetch it back out to get the ID
-                Self::key_by_name_inner(key_name, db)
+                Self::key_by_labels_inner(key_name, key_labels, db)
             }
             Err(e) => Err(e),
         }
     }
-    fn key_by_name_inner(key_name: &str, db: &mut SqliteConnection) -> Result<MetricKey<'a>> {
+    fn key_by_labels_inner(
+        key_name: &str,
+        key_labels: &str,
+        db: &mut SqliteConnection,
+    ) -> Result<MetricKey<'a>> {
         use crate::schema::metric_keys::dsl::*;
-        let query = metric_keys.filter(key.eq(key_name));
+        let query = metric_keys.filter(key.eq(key_name).and(labels.eq(key_labels)));
         let keys = query.load::<MetricKey>(db)?;
         keys.into_iter()
             .next()
-            .ok_or_else(|| MetricsError::KeyNotFound(key_name.to_string()))
+            .ok_or_else(|| MetricsError::KeyNotFound(series_name(key_name, key_labels).into()))
+    }
+}
+
+/// Canonical form of a key's labels as stored, sorted `name=value` pairs joined by `,`
+///
+/// `\`, `,` & `=` within names & values are escaped with a `\` so label sets never collide,
+/// e.g. `a="1,b=2"` & `a="1",b="2"`
+pub(crate) fn labels_string<'l, I>(labels: I) -> String
+where
+    I: IntoIterator<Item = (&'l str, &'l str)>,
+{
+    let mut labels: Vec<_> = labels.into_iter().collect();
+    labels.sort_unstable();
+    labels
+        .iter()
+        .map(|(name, value)| format!("{}={}", escape_label(name), escape_label(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn escape_label(part: &str) -> Cow<'_, str> {
+    let special = |c: char| matches!(c, '\\' | ',' | '=');
+    if !part.contains(special) {
+        return Cow::Borrowed(part);
+    }
+    let mut escaped = String::with_capacity(part.len() + 1);
+    for c in part.chars() {
+        if special(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Cow::Owned(escaped)
+}
+
+/// Parses labels stored by `labels_string()` back into `(name, value)` pairs
+pub(crate) fn parse_labels(labels: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut name = None;
+    let mut part = String::new();
+    let mut chars = labels.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => part.extend(chars.next()),
+            '=' if name.is_none() => name = Some(std::mem::take(&mut part)),
+            ',' => {
+                if let Some(name) = name.take() {
+                    pairs.push((name, std::mem::take(&mut part)));
+                }
+                part.clear();
+            }
+            c => part.push(c),
+        }
+    }
+    if let Some(name) = name {
+        pairs.push((name, part));
+    }
+    pairs
+}
+
+/// Name identifying a series, key name followed by its labels in braces if it has any
+pub(crate) fn series_name<'k>(key_name: &'k str, key_labels: &str) -> Cow<'k, str> {
+    match key_labels.is_empty() {
+        true => Cow::Borrowed(key_name),
+        false => Cow::Owned(format!("{}{{{}}}", key_name, key_labels)),
+    }
+}
+
+/// Database metadata entry, e.g. originating app & host
+#[derive(Insertable, Debug)]
+#[diesel(table_name = db_metadata)]
+struct NewDbMetadata<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+/// Sets (inserting or replacing) a database metadata entry
+pub(crate) fn set_db_metadata(
+    entry_name: &str,
+    entry_value: &str,
+    db: &mut SqliteConnection,
+) -> Result<()> {
+    use crate::schema::db_metadata::dsl::*;
+    diesel::insert_into(db_metadata)
+        .values(&NewDbMetadata {
+            name: entry_name,
+            value: entry_value,
+        })
+        .on_conflict(name)
+        .do_update()
+        .set(value.eq(entry_value))
+        .execute(db)?;
+    Ok(())
+}
+
+/// Caches metric key IDs by name, creating key entries as needed
+#[derive(Debug, Default)]
+pub(crate) struct KeyIdCache {
+    ids: HashMap<String, i64>,
+}
+impl KeyIdCache {
+    /// Returns whether ID of given key & labels has been looked up
+    pub(crate) fn contains(&self, key_name: &str, key_labels: &str) -> bool {
+        self.ids
+            .contains_key(series_name(key_name, key_labels).as_ref())
+    }
+    /// Returns ID of given key, looking it up (or creating it) on first use
+    pub(crate) fn id_for(&mut self, key_name: &str, db: &mut SqliteConnection) -> Result<i64> {
+        self.id_for_labels(key_name, "", db)
+    }
+    /// Returns ID of given key & labels, looking it up (or creating it) on first use
+    pub(crate) fn id_for_labels(
+        &mut self,
+        key_name: &str,
+        key_labels: &str,
+        db: &mut SqliteConnection,
+    ) -> Result<i64> {
+        let series = series_name(key_name, key_labels);
+        match self.ids.get(series.as_ref()) {
+            Some(key_id) => Ok(*key_id),
+            None => {
+                debug!("Looking up {}", series);
+                let key_id = MetricKey::key_by_labels(key_name, key_labels, db)?.id;
+                self.ids.insert(series.into_owned(), key_id);
+                Ok(key_id)
+            }
+        }
     }
 }
 
 /// Metric model for existing entries in sqlite database
-#[derive(Queryable, Debug, Identifiable, Associations)]
-#[diesel(belongs_to(MetricKey<'_>))]
+#[derive(Queryable, Selectable, Debug, Identifiable, Associations)]
+#[diesel(table_name = metrics, belongs_to(MetricKey<'_>))]
 #[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Metric {
     /// Unique ID of sample
     pub id: i64,
     /// Timestamp of sample
-    pub timestamp: f64,
+    pub timestamp: Timestamp,
     /// Key/name of sample
     pub metric_key_id: i64,
     /// Value of sample
-    pub value: f64,
+    pub value: Value,
+}
+
+/// New named text event, e.g. a version change
+#[derive(Insertable, Debug)]
+#[diesel(table_name = events)]
+pub(crate) struct NewEvent {
+    /// Timestamp of event
+    pub timestamp: f64,
+    /// Name of what changed
+    pub name: String,
+    /// Text value it changed to
+    pub value: String,
+}
+
+/// Named text event recorded with `SqliteExporter::record_event()`
+#[derive(Queryable, Selectable, Debug, Clone, PartialEq)]
+#[diesel(table_name = events)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RecordedEvent {
+    /// Timestamp of event
+    pub timestamp: Timestamp,
+    /// Name of what changed
+    pub name: String,
+    /// Text value it changed to
+    pub value: String,
+}
+
+/// New histogram bucket count of one flush window
+#[derive(Insertable, Debug)]
+#[diesel(table_name = histogram_buckets)]
+pub(crate) struct NewHistogramBucket {
+    /// Timestamp of flush ending the window
+    pub timestamp: f64,
+    /// Key/name of histogram
+    pub metric_key_id: i64,
+    /// Inclusive upper bound of bucket
+    pub upper_bound: f64,
+    /// Number of observations in bucket
+    pub count: i64,
+}
+
+/// Number of a histogram's observations within one bucket over one flush window, see
+/// `SqliteExporterBuilder::histogram_buckets()`
+#[derive(Queryable, Selectable, Debug, Clone, PartialEq)]
+#[diesel(table_name = histogram_buckets)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HistogramBucket {
+    /// Timestamp of flush ending the window
+    pub timestamp: Timestamp,
+    /// Inclusive upper bound of bucket, infinite for observations above every configured bound
+    pub upper_bound: f64,
+    /// Number of observations in bucket, observations in lower buckets not included
+    pub count: i64,
+}
+
+/// Rollup of a key's samples within one archive bucket, see `RetentionPolicy::archive()`
+#[derive(Queryable, Selectable, Debug, Clone, PartialEq)]
+#[diesel(table_name = metrics_archive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ArchivedMetric {
+    /// Start of bucket
+    pub timestamp: Timestamp,
+    /// Bucket length in seconds
+    pub resolution: f64,
+    /// Number of samples rolled up
+    pub sample_count: i64,
+    /// Smallest sample value
+    pub min_value: Value,
+    /// Largest sample value
+    pub max_value: Value,
+    /// Mean of sample values
+    pub mean_value: Value,
+}
+
+/// Quantile summary of a histogram computed by the application, stored as JSON
+#[cfg(feature = "histogram_summary")]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HistogramSummary {
+    /// Number of observations summarized
+    pub count: u64,
+    /// Smallest observation
+    pub min: f64,
+    /// Largest observation
+    pub max: f64,
+    /// 50th percentile
+    pub p50: f64,
+    /// 90th percentile
+    pub p90: f64,
+    /// 99th percentile
+    pub p99: f64,
+}
+
+/// A new histogram summary snapshot for storing into sqlite database
+#[cfg(feature = "histogram_summary")]
+#[derive(Insertable, Debug)]
+#[diesel(table_name = histogram_summaries)]
+pub(crate) struct NewHistogramSummary {
+    /// Timestamp of snapshot
+    pub timestamp: f64,
+    /// Key/name of snapshot
+    pub metric_key_id: i64,
+    /// JSON serialized `HistogramSummary`
+    pub summary: String,
 }
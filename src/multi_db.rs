@@ -0,0 +1,166 @@
+//! Reading several database files as one timestamp ordered stream
+use crate::models::{Timestamp, Value};
+use crate::{MetricsDb, Result};
+use diesel::SqliteConnection;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::path::{Path, PathBuf};
+
+/// Sample read by `MultiDbReader`, as `(source, key, labels, timestamp, value)` where `source` is
+/// the index of the database it came from in `MultiDbReader::paths()`
+pub type SourcedSample = (usize, String, String, Timestamp, Value);
+
+/// Reads samples of several databases (e.g. one per device) in global timestamp order without
+/// combining the files or loading them into memory, merging their streams as they're read
+///
+/// Databases are opened read-only & never migrated.
+pub struct MultiDbReader {
+    paths: Vec<PathBuf>,
+    dbs: Vec<SqliteConnection>,
+}
+
+impl MultiDbReader {
+    /// Opens given database files read-only
+    pub fn open<I, P>(paths: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let paths: Vec<PathBuf> = paths
+            .into_iter()
+            .map(|path| path.as_ref().to_path_buf())
+            .collect();
+        let dbs = paths
+            .iter()
+            .map(|path| MetricsDb::establish_read_only(path))
+            .collect::<Result<_>>()?;
+        Ok(MultiDbReader { paths, dbs })
+    }
+
+    /// Paths of opened databases, indexed by the source of each sample
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Streams every sample of every database in global timestamp order, samples with equal
+    /// timestamps ordered by source
+    pub fn samples(&mut self) -> Result<impl Iterator<Item = Result<SourcedSample>> + '_> {
+        let mut sources = self
+            .dbs
+            .iter_mut()
+            .map(MetricsDb::ordered_samples)
+            .collect::<Result<Vec<_>>>()?;
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        let mut errors = Vec::new();
+        for (source, rows) in sources.iter_mut().enumerate() {
+            match rows.next() {
+                Some(Ok((key, labels, timestamp, value))) => heap.push(HeapEntry {
+                    source,
+                    key,
+                    labels,
+                    timestamp,
+                    value,
+                }),
+                Some(Err(e)) => errors.push(e),
+                None => {}
+            }
+        }
+        Ok(std::iter::from_fn(move || {
+            if let Some(e) = errors.pop() {
+                return Some(Err(e));
+            }
+            let entry = heap.pop()?;
+            // refill from the source just taken from so the heap always holds each source's head
+            match sources[entry.source].next() {
+                Some(Ok((key, labels, timestamp, value))) => heap.push(HeapEntry {
+                    source: entry.source,
+                    key,
+                    labels,
+                    timestamp,
+                    value,
+                }),
+                Some(Err(e)) => errors.push(e),
+                None => {}
+            }
+            Some(Ok((
+                entry.source,
+                entry.key,
+                entry.labels,
+                entry.timestamp,
+                entry.value,
+            )))
+        }))
+    }
+}
+
+/// Head of a source's stream, ordered so `BinaryHeap` pops the earliest first
+struct HeapEntry {
+    source: usize,
+    key: String,
+    labels: String,
+    timestamp: Timestamp,
+    value: Value,
+}
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .timestamp
+            .0
+            .total_cmp(&self.timestamp.0)
+            .then_with(|| other.source.cmp(&self.source))
+    }
+}
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for HeapEntry {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{setup_db, InnerState};
+    use std::time::Duration;
+
+    fn populate(dir: &Path, name: &str, samples: &[(&str, f64, f64)]) -> PathBuf {
+        let path = dir.join(format!("{}.db", name));
+        let mut state = InnerState::new(Duration::from_secs(5), setup_db(&path).unwrap());
+        for (key, timestamp, value) in samples {
+            state
+                .queue_metric(Duration::from_secs_f64(*timestamp), key, "", *value)
+                .unwrap();
+        }
+        state.flush().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_merged_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = populate(dir.path(), "a", &[("x", 1.0, 1.0), ("x", 4.0, 4.0)]);
+        let b = populate(
+            dir.path(),
+            "b",
+            &[("y", 2.0, 2.0), ("y", 4.0, 5.0), ("y", 6.0, 6.0)],
+        );
+        let empty = populate(dir.path(), "empty", &[]);
+        let mut reader = MultiDbReader::open([&a, &b, &empty]).unwrap();
+        assert_eq!(reader.paths()[1], b);
+        let merged = reader
+            .samples()
+            .unwrap()
+            .map(|sample| sample.map(|(source, _, _, t, _)| (source, t.0)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            merged,
+            vec![(0, 1.0), (1, 2.0), (0, 4.0), (1, 4.0), (1, 6.0)]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Synchronous observer of recorded metrics, for asserting on instrumented code in tests
+use metrics::{GaugeValue, Key};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Default)]
+struct ObserverState {
+    records: Vec<(Key, f64)>,
+    counters: HashMap<Key, u64>,
+    gauges: HashMap<Key, f64>,
+}
+
+/// Records every `(key, value)` the exporter receives into memory, called synchronously as metrics
+/// are emitted so tests can assert without waiting on the worker to flush to the database
+///
+/// Values are what would be stored: counter totals, resulting gauge values & histogram samples.
+/// Gauge deadbands aren't applied.
+///
+/// ```
+/// # use metrics::{Key, Recorder};
+/// # use metrics_sqlite::{RecordingObserver, SqliteExporter};
+/// let observer = RecordingObserver::new();
+/// let exporter = SqliteExporter::builder()
+///     .observer(observer.clone())
+///     .build(":memory:")
+///     .unwrap();
+/// let counter = exporter.register_counter(&Key::from_name("requests"));
+/// counter.increment(2);
+/// counter.increment(3);
+/// assert_eq!(observer.values_for("requests"), vec![2.0, 5.0]);
+/// ```
+#[derive(Clone, Default)]
+pub struct RecordingObserver {
+    state: Arc<Mutex<ObserverState>>,
+}
+
+impl RecordingObserver {
+    /// Creates an empty observer, clone it to keep a handle after passing it to the builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns all records in emitted order
+    pub fn records(&self) -> Vec<(Key, f64)> {
+        self.lock().records.clone()
+    }
+
+    /// Returns values recorded for given key name, regardless of labels
+    pub fn values_for(&self, key_name: &str) -> Vec<f64> {
+        self.lock()
+            .records
+            .iter()
+            .filter(|(key, _)| key.name() == key_name)
+            .map(|(_, value)| *value)
+            .collect()
+    }
+
+    /// Removes all records, counter totals & gauge values carry on
+    pub fn clear(&self) {
+        self.lock().records.clear();
+    }
+
+    pub(crate) fn increment_counter(&self, key: &Key, value: u64) {
+        let mut state = self.lock();
+        let entry = state.counters.entry(key.clone()).or_insert(0);
+        *entry += value;
+        let total = *entry;
+        state.records.push((key.clone(), total as f64));
+    }
+
+    pub(crate) fn absolute_counter(&self, key: &Key, value: u64) {
+        let mut state = self.lock();
+        state.counters.insert(key.clone(), value);
+        state.records.push((key.clone(), value as f64));
+    }
+
+    pub(crate) fn update_gauge(&self, key: &Key, value: GaugeValue) {
+        let mut state = self.lock();
+        let entry = state.gauges.entry(key.clone()).or_insert(0.0);
+        *entry = value.update_value(*entry);
+        let value = *entry;
+        state.records.push((key.clone(), value));
+    }
+
+    pub(crate) fn record_histogram(&self, key: &Key, value: f64) {
+        self.lock().records.push((key.clone(), value));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ObserverState> {
+        // a panicking test thread shouldn't hide records from the others
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
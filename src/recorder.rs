@@ -1,153 +1,89 @@
-use crate::{Event, RegisterType, SqliteExporter};
+#[cfg(feature = "test-util")]
+use crate::RecordingObserver;
+use crate::{Event, MetricKind, SqliteExporter};
 use metrics::{
     Counter, CounterFn, Gauge, GaugeFn, GaugeValue, Histogram, HistogramFn, Key, KeyName, Recorder,
     SharedString, Unit,
 };
+use std::sync::atomic::AtomicU64;
 use std::sync::mpsc::SyncSender;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 pub(crate) struct Handle {
     sender: SyncSender<Event>,
     key: Key,
+    dropped: Arc<AtomicU64>,
+    #[cfg(feature = "test-util")]
+    observer: Option<RecordingObserver>,
+}
+impl Handle {
+    /// Sends event built with current time to the worker, counting it as dropped on failure
+    fn send(&self, event: impl FnOnce(Duration) -> Event) {
+        crate::send_counting_drops(&self.sender, &self.dropped, SystemTime::now(), event);
+    }
 }
 impl CounterFn for Handle {
     fn increment(&self, value: u64) {
-        match SystemTime::UNIX_EPOCH.elapsed() {
-            Ok(timestamp) => {
-                if let Err(_e) = self.sender.try_send(Event::IncrementCounter(
-                    timestamp,
-                    self.key.clone(),
-                    value,
-                )) {
-                    #[cfg(feature = "log_dropped_metrics")]
-                    error!(
-                        "Error sending metric to SQLite thread: {}, dropping metric",
-                        _e
-                    );
-                }
-            }
-            Err(_e) => {
-                #[cfg(feature = "log_dropped_metrics")]
-                error!("Failed to get system time: {}, dropping metric", _e);
-            }
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.increment_counter(&self.key, value);
         }
+        self.send(|timestamp| Event::IncrementCounter(timestamp, self.key.clone(), value));
     }
 
     fn absolute(&self, value: u64) {
-        match SystemTime::UNIX_EPOCH.elapsed() {
-            Ok(timestamp) => {
-                if let Err(_e) =
-                    self.sender
-                        .try_send(Event::AbsoluteCounter(timestamp, self.key.clone(), value))
-                {
-                    #[cfg(feature = "log_dropped_metrics")]
-                    error!(
-                        "Error sending metric to SQLite thread: {}, dropping metric",
-                        _e
-                    );
-                }
-            }
-            Err(_e) => {
-                #[cfg(feature = "log_dropped_metrics")]
-                error!("Failed to get system time: {}, dropping metric", _e);
-            }
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.absolute_counter(&self.key, value);
         }
+        self.send(|timestamp| Event::AbsoluteCounter(timestamp, self.key.clone(), value));
     }
 }
 impl GaugeFn for Handle {
     fn increment(&self, value: f64) {
-        match SystemTime::UNIX_EPOCH.elapsed() {
-            Ok(timestamp) => {
-                if let Err(_e) = self.sender.try_send(Event::UpdateGauge(
-                    timestamp,
-                    self.key.clone(),
-                    GaugeValue::Increment(value),
-                )) {
-                    #[cfg(feature = "log_dropped_metrics")]
-                    error!(
-                        "Error sending metric to SQLite thread: {}, dropping metric",
-                        _e
-                    );
-                }
-            }
-            Err(_e) => {
-                #[cfg(feature = "log_dropped_metrics")]
-                error!("Failed to get system time: {}, dropping metric", _e);
-            }
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.update_gauge(&self.key, GaugeValue::Increment(value));
         }
+        self.send(|timestamp| {
+            Event::UpdateGauge(timestamp, self.key.clone(), GaugeValue::Increment(value))
+        });
     }
 
     fn decrement(&self, value: f64) {
-        match SystemTime::UNIX_EPOCH.elapsed() {
-            Ok(timestamp) => {
-                if let Err(_e) = self.sender.try_send(Event::UpdateGauge(
-                    timestamp,
-                    self.key.clone(),
-                    GaugeValue::Decrement(value),
-                )) {
-                    #[cfg(feature = "log_dropped_metrics")]
-                    error!(
-                        "Error sending metric to SQLite thread: {}, dropping metric",
-                        _e
-                    );
-                }
-            }
-            Err(_e) => {
-                #[cfg(feature = "log_dropped_metrics")]
-                error!("Failed to get system time: {}, dropping metric", _e);
-            }
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.update_gauge(&self.key, GaugeValue::Decrement(value));
         }
+        self.send(|timestamp| {
+            Event::UpdateGauge(timestamp, self.key.clone(), GaugeValue::Decrement(value))
+        });
     }
 
     fn set(&self, value: f64) {
-        match SystemTime::UNIX_EPOCH.elapsed() {
-            Ok(timestamp) => {
-                if let Err(_e) = self.sender.try_send(Event::UpdateGauge(
-                    timestamp,
-                    self.key.clone(),
-                    GaugeValue::Absolute(value),
-                )) {
-                    #[cfg(feature = "log_dropped_metrics")]
-                    error!(
-                        "Error sending metric to SQLite thread: {}, dropping metric",
-                        _e
-                    );
-                }
-            }
-            Err(_e) => {
-                #[cfg(feature = "log_dropped_metrics")]
-                error!("Failed to get system time: {}, dropping metric", _e);
-            }
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.update_gauge(&self.key, GaugeValue::Absolute(value));
         }
+        self.send(|timestamp| {
+            Event::UpdateGauge(timestamp, self.key.clone(), GaugeValue::Absolute(value))
+        });
     }
 }
 impl HistogramFn for Handle {
     fn record(&self, value: f64) {
-        match SystemTime::UNIX_EPOCH.elapsed() {
-            Ok(timestamp) => {
-                if let Err(_e) =
-                    self.sender
-                        .try_send(Event::UpdateHistogram(timestamp, self.key.clone(), value))
-                {
-                    #[cfg(feature = "log_dropped_metrics")]
-                    error!(
-                        "Error sending metric to SQLite thread: {}, dropping metric",
-                        _e
-                    );
-                }
-            }
-            Err(_e) => {
-                #[cfg(feature = "log_dropped_metrics")]
-                error!("Failed to get system time: {}, dropping metric", _e);
-            }
+        #[cfg(feature = "test-util")]
+        if let Some(observer) = &self.observer {
+            observer.record_histogram(&self.key, value);
         }
+        self.send(|timestamp| Event::UpdateHistogram(timestamp, self.key.clone(), value));
     }
 }
 impl Recorder for SqliteExporter {
     fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
         if let Err(e) = self.sender.try_send(Event::DescribeKey(
-            RegisterType::Counter,
+            MetricKind::Counter,
             key,
             unit,
             description,
@@ -158,7 +94,7 @@ impl Recorder for SqliteExporter {
 
     fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
         if let Err(e) = self.sender.try_send(Event::DescribeKey(
-            RegisterType::Gauge,
+            MetricKind::Gauge,
             key,
             unit,
             description,
@@ -169,7 +105,7 @@ impl Recorder for SqliteExporter {
 
     fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
         if let Err(e) = self.sender.try_send(Event::DescribeKey(
-            RegisterType::Histogram,
+            MetricKind::Histogram,
             key,
             unit,
             description,
@@ -184,9 +120,12 @@ impl Recorder for SqliteExporter {
         let handle = Arc::new(Handle {
             sender,
             key: key.clone(),
+            dropped: self.dropped.clone(),
+            #[cfg(feature = "test-util")]
+            observer: self.observer.clone(),
         });
         if let Err(e) = self.sender.try_send(Event::RegisterKey(
-            RegisterType::Counter,
+            MetricKind::Counter,
             key.clone(),
             handle.clone(),
         )) {
@@ -200,9 +139,12 @@ impl Recorder for SqliteExporter {
         let handle = Arc::new(Handle {
             sender,
             key: key.clone(),
+            dropped: self.dropped.clone(),
+            #[cfg(feature = "test-util")]
+            observer: self.observer.clone(),
         });
         if let Err(e) = self.sender.try_send(Event::RegisterKey(
-            RegisterType::Gauge,
+            MetricKind::Gauge,
             key.clone(),
             handle.clone(),
         )) {
@@ -216,9 +158,12 @@ impl Recorder for SqliteExporter {
         let handle = Arc::new(Handle {
             sender,
             key: key.clone(),
+            dropped: self.dropped.clone(),
+            #[cfg(feature = "test-util")]
+            observer: self.observer.clone(),
         });
         if let Err(e) = self.sender.try_send(Event::RegisterKey(
-            RegisterType::Histogram,
+            MetricKind::Histogram,
             key.clone(),
             handle.clone(),
         )) {
@@ -0,0 +1,225 @@
+//! Retention policy used by periodic house keeping
+use crate::{MetricsError, Result};
+use std::time::Duration;
+
+/// Adapts house keeping interval to database growth, running sooner when growing fast & later
+/// when idle, keeping disk usage stable for bursty workloads
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AdaptiveHousekeeping {
+    /// Shortest interval between runs
+    pub min: Duration,
+    /// Longest interval between runs, used when database isn't growing
+    pub max: Duration,
+    /// Growth in bytes to aim for between runs
+    pub target_bytes: u64,
+}
+
+impl AdaptiveHousekeeping {
+    /// Creates adaptive house keeping bounded by `min` & `max` intervals
+    pub fn new(min: Duration, max: Duration, target_bytes: u64) -> Self {
+        AdaptiveHousekeeping {
+            min,
+            max,
+            target_bytes,
+        }
+    }
+
+    /// Returns interval expected to see `target_bytes` of growth given `grown_bytes` over `elapsed`
+    pub(crate) fn next_interval(&self, grown_bytes: u64, elapsed: Duration) -> Duration {
+        if grown_bytes == 0 {
+            return self.max;
+        }
+        let interval = elapsed.as_secs_f64() * self.target_bytes as f64 / grown_bytes as f64;
+        Duration::from_secs_f64(interval.min(self.max.as_secs_f64())).max(self.min)
+    }
+}
+
+/// Length of buckets samples are rolled up into when archived
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArchiveResolution {
+    /// One rollup per key per minute
+    Minute,
+    /// One rollup per key per hour
+    Hour,
+}
+
+impl ArchiveResolution {
+    /// Bucket length in seconds
+    pub fn seconds(&self) -> f64 {
+        match self {
+            ArchiveResolution::Minute => 60.0,
+            ArchiveResolution::Hour => 60.0 * 60.0,
+        }
+    }
+}
+
+/// Moves samples older than `after` out of the metrics table into rollups in the archive table,
+/// keeping recent queries fast while retaining coarse history
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ArchivePolicy {
+    /// Age after which samples are archived
+    pub after: Duration,
+    /// Bucket length of rollups
+    pub resolution: ArchiveResolution,
+}
+
+impl ArchivePolicy {
+    /// Creates policy archiving samples older than `after` into `resolution` rollups
+    pub fn new(after: Duration, resolution: ArchiveResolution) -> Self {
+        ArchivePolicy { after, resolution }
+    }
+}
+
+/// Describes how often periodic house keeping runs & what it removes
+///
+/// ```
+/// # use metrics_sqlite::RetentionPolicy;
+/// # use std::time::Duration;
+/// let policy = RetentionPolicy::new(Duration::from_secs(60))
+///     .retention(Duration::from_secs(60 * 60 * 24 * 7))
+///     .record_limit(1_000_000);
+/// assert!(policy.validate().is_ok());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    /// How often house keeping runs
+    pub housekeeping_interval: Duration,
+    /// How long data is kept before deleting, None to keep regardless of age
+    pub retention: Option<Duration>,
+    /// Max number of records to keep, None for no limit
+    ///
+    /// Anything over limit + 25% of limit is removed, oldest first
+    pub record_limit: Option<usize>,
+    /// Number of most recent sessions to keep, None for no limit
+    pub keep_sessions: Option<usize>,
+    /// Adapts interval to database growth, `housekeeping_interval` being the initial interval
+    pub adaptive: Option<AdaptiveHousekeeping>,
+    /// Archives old samples into rollups before other removal, None to keep samples as recorded
+    pub(crate) archive: Option<ArchivePolicy>,
+}
+
+impl RetentionPolicy {
+    /// Creates a policy running every `housekeeping_interval`, set retention and/or record limit
+    /// on it
+    pub fn new(housekeeping_interval: Duration) -> Self {
+        RetentionPolicy {
+            housekeeping_interval,
+            retention: None,
+            record_limit: None,
+            keep_sessions: None,
+            adaptive: None,
+            archive: None,
+        }
+    }
+
+    /// Sets how long data is kept before deleting
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Sets max number of records to keep
+    pub fn record_limit(mut self, record_limit: usize) -> Self {
+        self.record_limit = Some(record_limit);
+        self
+    }
+
+    /// Keeps only the last `sessions` sessions (runs), deleting samples of older ones
+    ///
+    /// Sessions are split using default `SessionThresholds`
+    pub fn keep_last_sessions(mut self, sessions: usize) -> Self {
+        self.keep_sessions = Some(sessions);
+        self
+    }
+
+    /// Adapts interval between `adaptive.min` & `adaptive.max` based on database growth
+    pub fn adaptive(mut self, adaptive: AdaptiveHousekeeping) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Archives samples older than `archive.after` into rollups instead of keeping them as recorded
+    ///
+    /// Retention also applies to archived rollups, while record & session limits only apply to
+    /// samples still in the metrics table
+    pub fn archive(mut self, archive: ArchivePolicy) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Interval to wait before first run
+    pub(crate) fn initial_interval(&self) -> Duration {
+        match &self.adaptive {
+            Some(adaptive) => self.housekeeping_interval.clamp(adaptive.min, adaptive.max),
+            None => self.housekeeping_interval,
+        }
+    }
+
+    /// Checks policy for nonsensical settings
+    pub fn validate(&self) -> Result<()> {
+        if self.housekeeping_interval.is_zero() {
+            return Err(MetricsError::InvalidRetentionPolicy(
+                "housekeeping interval must be greater than zero",
+            ));
+        }
+        if self.retention == Some(Duration::ZERO) {
+            return Err(MetricsError::InvalidRetentionPolicy(
+                "retention must be greater than zero",
+            ));
+        }
+        if self.record_limit == Some(0) {
+            return Err(MetricsError::InvalidRetentionPolicy(
+                "record limit must be greater than zero",
+            ));
+        }
+        if self.keep_sessions == Some(0) {
+            return Err(MetricsError::InvalidRetentionPolicy(
+                "sessions to keep must be greater than zero",
+            ));
+        }
+        if let Some(adaptive) = &self.adaptive {
+            if adaptive.min.is_zero() || adaptive.min > adaptive.max {
+                return Err(MetricsError::InvalidRetentionPolicy(
+                    "adaptive interval bounds must be greater than zero with min <= max",
+                ));
+            }
+            if adaptive.target_bytes == 0 {
+                return Err(MetricsError::InvalidRetentionPolicy(
+                    "adaptive target bytes must be greater than zero",
+                ));
+            }
+        }
+        if let Some(archive) = &self.archive {
+            if archive.after.is_zero() {
+                return Err(MetricsError::InvalidRetentionPolicy(
+                    "archive age must be greater than zero",
+                ));
+            }
+        }
+        if self.retention.is_none()
+            && self.record_limit.is_none()
+            && self.keep_sessions.is_none()
+            && self.archive.is_none()
+        {
+            return Err(MetricsError::InvalidRetentionPolicy(
+                "either retention, record limit, sessions to keep or archive must be set",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_interval() {
+        let secs = Duration::from_secs;
+        let adaptive = AdaptiveHousekeeping::new(secs(10), secs(600), 1000);
+        assert_eq!(adaptive.next_interval(0, secs(60)), secs(600));
+        assert_eq!(adaptive.next_interval(500, secs(60)), secs(120));
+        assert_eq!(adaptive.next_interval(1_000_000, secs(60)), secs(10));
+        assert_eq!(adaptive.next_interval(10, secs(60)), secs(600));
+    }
+}
@@ -4,6 +4,8 @@ table! {
         timestamp -> Double,
         metric_key_id -> BigInt,
         value -> Double,
+        timestamp_text -> Nullable<Text>,
+        call_count -> Nullable<BigInt>,
     }
 }
 table! {
@@ -12,8 +14,79 @@ table! {
         key -> Text,
         unit -> Text,
         description -> Text,
+        kind -> Nullable<Text>,
+        labels -> Text,
+    }
+}
+table! {
+    histogram_summaries (id) {
+        id -> BigInt,
+        timestamp -> Double,
+        metric_key_id -> BigInt,
+        summary -> Text,
+    }
+}
+table! {
+    metric_key_attributes (id) {
+        id -> BigInt,
+        metric_key_id -> BigInt,
+        name -> Text,
+        value -> Text,
+    }
+}
+table! {
+    db_metadata (name) {
+        name -> Text,
+        value -> Text,
+    }
+}
+table! {
+    session_annotations (id) {
+        id -> BigInt,
+        session_start -> Double,
+        note -> Text,
+    }
+}
+table! {
+    metrics_archive (id) {
+        id -> BigInt,
+        metric_key_id -> BigInt,
+        timestamp -> Double,
+        resolution -> Double,
+        sample_count -> BigInt,
+        min_value -> Double,
+        max_value -> Double,
+        mean_value -> Double,
+    }
+}
+table! {
+    events (id) {
+        id -> BigInt,
+        timestamp -> Double,
+        name -> Text,
+        value -> Text,
+    }
+}
+table! {
+    histogram_buckets (id) {
+        id -> BigInt,
+        timestamp -> Double,
+        metric_key_id -> BigInt,
+        upper_bound -> Double,
+        count -> BigInt,
     }
 }
 joinable!(metrics -> metric_keys (metric_key_id));
-allow_tables_to_appear_in_same_query!(metrics, metric_keys);
+joinable!(histogram_summaries -> metric_keys (metric_key_id));
+joinable!(metric_key_attributes -> metric_keys (metric_key_id));
+joinable!(metrics_archive -> metric_keys (metric_key_id));
+joinable!(histogram_buckets -> metric_keys (metric_key_id));
+allow_tables_to_appear_in_same_query!(
+    metrics,
+    metric_keys,
+    histogram_summaries,
+    metric_key_attributes,
+    metrics_archive,
+    histogram_buckets
+);
 // allow_tables_to_appear_in_same_query!(counters,);